@@ -1,23 +1,42 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use ini::{Ini, Properties};
-use std::collections::HashMap;
+use std::{collections::HashMap, env};
+
+use crate::proxy_protocol;
 
 #[derive(Debug, Clone)]
 pub struct Proxy {
     pub server_addr: String,
     pub server_port: u16,
     pub proxy_type: String,
+    pub use_encryption: bool,
+    pub use_compression: bool,
+    pub plugin: Option<String>,
+    pub plugin_params: HashMap<String, String>,
+    pub proxy_protocol_version: Option<proxy_protocol::Version>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ClientCommonConfig {
     server_addr: String,
     server_port: u16,
+    connect_server_local_ip: String,
+    dns_server: String,
     pool_count: u32,
     tcp_mux: bool,
+    protocol: String,
+    http_proxy: String,
     token: String,
     heartbeat_interval: u32,
     heartbeat_timeout: u32,
+    login_fail_exit: bool,
+    udp_packet_size: usize,
+    tls_enable: bool,
+    tls_cert_file: String,
+    tls_key_file: String,
+    tls_trusted_ca_file: String,
+    tls_server_name: String,
+    disable_custom_tls_first_byte: bool,
 }
 
 impl ClientCommonConfig {
@@ -25,11 +44,24 @@ impl ClientCommonConfig {
         ClientCommonConfig {
             server_addr: "0.0.0.0".to_string(),
             server_port: 7000,
+            connect_server_local_ip: "".to_string(),
+            dns_server: "".to_string(),
             pool_count: 1,
             tcp_mux: true,
+            protocol: "tcp".to_string(),
+            // like frp, fall back to the environment when http_proxy isn't configured
+            http_proxy: env::var("http_proxy").unwrap_or_default(),
             token: "".to_string(),
             heartbeat_interval: 30,
             heartbeat_timeout: 90,
+            login_fail_exit: true,
+            udp_packet_size: 1500,
+            tls_enable: false,
+            tls_cert_file: "".to_string(),
+            tls_key_file: "".to_string(),
+            tls_trusted_ca_file: "".to_string(),
+            tls_server_name: "".to_string(),
+            disable_custom_tls_first_byte: false,
         }
     }
 }
@@ -40,6 +72,11 @@ pub struct ClientTcpConfig {
     local_ip: String,
     local_port: u16,
     pub remote_port: u16,
+    pub use_encryption: bool,
+    pub use_compression: bool,
+    pub plugin: Option<String>,
+    pub plugin_params: HashMap<String, String>,
+    pub proxy_protocol_version: Option<proxy_protocol::Version>,
 }
 
 impl ClientTcpConfig {
@@ -49,6 +86,90 @@ impl ClientTcpConfig {
             local_ip: "127.0.0.1".to_string(),
             local_port: 0,
             remote_port: 0,
+            use_encryption: false,
+            use_compression: false,
+            plugin: None,
+            plugin_params: HashMap::new(),
+            proxy_protocol_version: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientUdpConfig {
+    pub service_type: String,
+    local_ip: String,
+    local_port: u16,
+    pub remote_port: u16,
+    pub use_encryption: bool,
+    pub use_compression: bool,
+}
+
+impl ClientUdpConfig {
+    pub fn new() -> ClientUdpConfig {
+        ClientUdpConfig {
+            service_type: "udp".to_string(),
+            local_ip: "127.0.0.1".to_string(),
+            local_port: 0,
+            remote_port: 0,
+            use_encryption: false,
+            use_compression: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientStcpConfig {
+    pub service_type: String,
+    local_ip: String,
+    local_port: u16,
+    pub sk: String,
+    pub allow_users: Vec<String>,
+    pub use_encryption: bool,
+    pub use_compression: bool,
+    pub plugin: Option<String>,
+    pub plugin_params: HashMap<String, String>,
+    pub proxy_protocol_version: Option<proxy_protocol::Version>,
+}
+
+impl ClientStcpConfig {
+    pub fn new(stype: String) -> ClientStcpConfig {
+        ClientStcpConfig {
+            service_type: stype,
+            local_ip: "127.0.0.1".to_string(),
+            local_port: 0,
+            sk: "".to_string(),
+            allow_users: Vec::new(),
+            use_encryption: false,
+            use_compression: false,
+            plugin: None,
+            plugin_params: HashMap::new(),
+            proxy_protocol_version: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientVisitorConfig {
+    pub service_type: String,
+    pub server_name: String,
+    pub sk: String,
+    pub bind_addr: String,
+    pub bind_port: u16,
+    pub use_encryption: bool,
+    pub use_compression: bool,
+}
+
+impl ClientVisitorConfig {
+    pub fn new(stype: String) -> ClientVisitorConfig {
+        ClientVisitorConfig {
+            service_type: stype,
+            server_name: "".to_string(),
+            sk: "".to_string(),
+            bind_addr: "127.0.0.1".to_string(),
+            bind_port: 0,
+            use_encryption: false,
+            use_compression: false,
         }
     }
 }
@@ -60,6 +181,11 @@ pub struct ClientWebConfig {
     local_port: u16,
     pub custom_domains: Option<String>,
     pub subdomain: Option<String>,
+    pub use_encryption: bool,
+    pub use_compression: bool,
+    pub plugin: Option<String>,
+    pub plugin_params: HashMap<String, String>,
+    pub proxy_protocol_version: Option<proxy_protocol::Version>,
 }
 
 impl ClientWebConfig {
@@ -70,6 +196,11 @@ impl ClientWebConfig {
             local_port: 0,
             custom_domains: None,
             subdomain: None,
+            use_encryption: false,
+            use_compression: false,
+            plugin: None,
+            plugin_params: HashMap::new(),
+            proxy_protocol_version: None,
         }
     }
 
@@ -86,29 +217,48 @@ impl ClientWebConfig {
 pub struct Config {
     common: ClientCommonConfig,
     pub tcp_configs: HashMap<String, ClientTcpConfig>,
+    pub udp_configs: HashMap<String, ClientUdpConfig>,
     pub web_configs: HashMap<String, ClientWebConfig>,
+    pub stcp_configs: HashMap<String, ClientStcpConfig>,
+    pub visitor_configs: HashMap<String, ClientVisitorConfig>,
 }
 
 impl Config {
     pub fn new() -> Self {
-        let mut common: ClientCommonConfig = ClientCommonConfig::new();
-        let mut tcp_configs: HashMap<String, ClientTcpConfig> = HashMap::new();
-        let mut web_configs: HashMap<String, ClientWebConfig> = HashMap::new();
+        let common: ClientCommonConfig = ClientCommonConfig::new();
+        let tcp_configs: HashMap<String, ClientTcpConfig> = HashMap::new();
+        let udp_configs: HashMap<String, ClientUdpConfig> = HashMap::new();
+        let web_configs: HashMap<String, ClientWebConfig> = HashMap::new();
+        let stcp_configs: HashMap<String, ClientStcpConfig> = HashMap::new();
+        let visitor_configs: HashMap<String, ClientVisitorConfig> = HashMap::new();
 
         Self {
             common,
             tcp_configs,
+            udp_configs,
             web_configs,
+            stcp_configs,
+            visitor_configs,
         }
     }
 
     pub fn load_config(&mut self, config_file: &str) -> Result<()> {
         let i = Ini::load_from_file(config_file).unwrap();
+        self.load_ini(&i)
+    }
+
+    /// Like `load_config`, for a configuration already read into memory.
+    pub fn load_config_str(&mut self, content: &str) -> Result<()> {
+        let i = Ini::load_from_str(content)?;
+        self.load_ini(&i)
+    }
+
+    fn load_ini(&mut self, i: &Ini) -> Result<()> {
         for (sec, prop) in i.iter() {
             if "common".eq(sec.unwrap()) {
-                self.parse_common_config(sec.unwrap(), &prop);
+                self.parse_common_config(sec.unwrap(), &prop)?;
             } else {
-                self.parse_proxy_config(sec.unwrap(), &i, &prop);
+                self.parse_proxy_config(sec.unwrap(), i, &prop)?;
             }
         }
 
@@ -127,6 +277,81 @@ impl Config {
         &self.common.token
     }
 
+    pub fn pool_count(&self) -> u32 {
+        self.common.pool_count
+    }
+
+    pub fn tcp_mux(&self) -> bool {
+        self.common.tcp_mux
+    }
+
+    pub fn protocol(&self) -> &str {
+        &self.common.protocol
+    }
+
+    pub fn connect_server_local_ip(&self) -> &str {
+        &self.common.connect_server_local_ip
+    }
+
+    pub fn dns_server(&self) -> &str {
+        &self.common.dns_server
+    }
+
+    pub fn http_proxy(&self) -> &str {
+        &self.common.http_proxy
+    }
+
+    pub fn heartbeat_interval(&self) -> u32 {
+        self.common.heartbeat_interval
+    }
+
+    pub fn heartbeat_timeout(&self) -> u32 {
+        self.common.heartbeat_timeout
+    }
+
+    pub fn login_fail_exit(&self) -> bool {
+        self.common.login_fail_exit
+    }
+
+    pub fn udp_packet_size(&self) -> usize {
+        self.common.udp_packet_size
+    }
+
+    pub fn tls_enable(&self) -> bool {
+        self.common.tls_enable
+    }
+
+    pub fn tls_cert_file(&self) -> &str {
+        &self.common.tls_cert_file
+    }
+
+    pub fn tls_key_file(&self) -> &str {
+        &self.common.tls_key_file
+    }
+
+    pub fn tls_trusted_ca_file(&self) -> &str {
+        &self.common.tls_trusted_ca_file
+    }
+
+    pub fn tls_server_name(&self) -> &str {
+        &self.common.tls_server_name
+    }
+
+    pub fn disable_custom_tls_first_byte(&self) -> bool {
+        self.common.disable_custom_tls_first_byte
+    }
+
+    /// Names of all proxies this client registers with frps.
+    pub fn proxy_names(&self) -> Vec<String> {
+        self.tcp_configs
+            .keys()
+            .chain(self.udp_configs.keys())
+            .chain(self.web_configs.keys())
+            .chain(self.stcp_configs.keys())
+            .cloned()
+            .collect()
+    }
+
     pub fn get_proxy(&self, proxy_name: &str) -> Result<Proxy> {
         if self.tcp_configs.contains_key(proxy_name) {
             let config = self.tcp_configs.get(proxy_name).unwrap();
@@ -135,6 +360,24 @@ impl Config {
                 server_addr: config.local_ip.clone(),
                 server_port: config.local_port,
                 proxy_type: "tcp".to_string(),
+                use_encryption: config.use_encryption,
+                use_compression: config.use_compression,
+                plugin: config.plugin.clone(),
+                plugin_params: config.plugin_params.clone(),
+                proxy_protocol_version: config.proxy_protocol_version,
+            })
+        } else if self.udp_configs.contains_key(proxy_name) {
+            let config = self.udp_configs.get(proxy_name).unwrap();
+
+            Ok(Proxy {
+                server_addr: config.local_ip.clone(),
+                server_port: config.local_port,
+                proxy_type: "udp".to_string(),
+                use_encryption: config.use_encryption,
+                use_compression: config.use_compression,
+                plugin: None,
+                plugin_params: HashMap::new(),
+                proxy_protocol_version: None,
             })
         } else if self.web_configs.contains_key(proxy_name) {
             let config = self.web_configs.get(proxy_name).unwrap();
@@ -143,6 +386,24 @@ impl Config {
                 server_addr: config.local_ip.clone(),
                 server_port: config.local_port,
                 proxy_type: "web".to_string(),
+                use_encryption: config.use_encryption,
+                use_compression: config.use_compression,
+                plugin: config.plugin.clone(),
+                plugin_params: config.plugin_params.clone(),
+                proxy_protocol_version: config.proxy_protocol_version,
+            })
+        } else if self.stcp_configs.contains_key(proxy_name) {
+            let config = self.stcp_configs.get(proxy_name).unwrap();
+
+            Ok(Proxy {
+                server_addr: config.local_ip.clone(),
+                server_port: config.local_port,
+                proxy_type: config.service_type.clone(),
+                use_encryption: config.use_encryption,
+                use_compression: config.use_compression,
+                plugin: config.plugin.clone(),
+                plugin_params: config.plugin_params.clone(),
+                proxy_protocol_version: config.proxy_protocol_version,
             })
         } else {
             Err(anyhow!("no such proxy"))
@@ -162,7 +423,25 @@ impl Config {
                         self.common.tcp_mux = false
                     }
                 }
+                "protocol" => self.common.protocol = v.to_string(),
+                "http_proxy" => self.common.http_proxy = v.to_string(),
+                "connect_server_local_ip" => self.common.connect_server_local_ip = v.to_string(),
+                "dns_server" => self.common.dns_server = v.to_string(),
                 "pool_count" => self.common.pool_count = v.parse::<u32>().unwrap(),
+                "udp_packet_size" => self.common.udp_packet_size = v.parse::<usize>().unwrap(),
+                "login_fail_exit" => {
+                    if v.eq(&"false".to_string()) {
+                        self.common.login_fail_exit = false
+                    }
+                }
+                "tls_enable" => self.common.tls_enable = v == "true",
+                "tls_cert_file" => self.common.tls_cert_file = v.to_string(),
+                "tls_key_file" => self.common.tls_key_file = v.to_string(),
+                "tls_trusted_ca_file" => self.common.tls_trusted_ca_file = v.to_string(),
+                "tls_server_name" => self.common.tls_server_name = v.to_string(),
+                "disable_custom_tls_first_byte" => {
+                    self.common.disable_custom_tls_first_byte = v == "true"
+                }
                 _ => println!("dont support {}", k),
             }
         }
@@ -182,12 +461,40 @@ impl Config {
                     "local_ip" => tcp_proxy_config.local_ip = v.to_string(),
                     "local_port" => tcp_proxy_config.local_port = v.parse::<u16>().unwrap(),
                     "remote_port" => tcp_proxy_config.remote_port = v.parse::<u16>().unwrap(),
+                    "use_encryption" => tcp_proxy_config.use_encryption = v == "true",
+                    "use_compression" => tcp_proxy_config.use_compression = v == "true",
+                    "plugin" => tcp_proxy_config.plugin = Some(v.to_string()),
+                    "proxy_protocol_version" => {
+                        let version = v.parse().with_context(|| format!("proxy [{}]", name))?;
+                        tcp_proxy_config.proxy_protocol_version = Some(version)
+                    }
+                    k if k.starts_with("plugin_") => {
+                        tcp_proxy_config
+                            .plugin_params
+                            .insert(k.to_string(), v.to_string());
+                    }
                     "type" => (),
                     _ => println!("invalid key {}", k),
                 }
             }
 
             self.tcp_configs.insert(name.to_string(), tcp_proxy_config);
+        } else if stype.eq("udp") {
+            let mut udp_proxy_config = ClientUdpConfig::new();
+
+            for (k, v) in prop.iter() {
+                match k {
+                    "local_ip" => udp_proxy_config.local_ip = v.to_string(),
+                    "local_port" => udp_proxy_config.local_port = v.parse::<u16>().unwrap(),
+                    "remote_port" => udp_proxy_config.remote_port = v.parse::<u16>().unwrap(),
+                    "use_encryption" => udp_proxy_config.use_encryption = v == "true",
+                    "use_compression" => udp_proxy_config.use_compression = v == "true",
+                    "type" => (),
+                    _ => println!("invalid key {}", k),
+                }
+            }
+
+            self.udp_configs.insert(name.to_string(), udp_proxy_config);
         } else if stype.eq("http") || stype.eq("https") {
             let mut web_proxy_config = ClientWebConfig::new(stype.to_string());
 
@@ -197,12 +504,73 @@ impl Config {
                     "local_port" => web_proxy_config.local_port = v.parse::<u16>().unwrap(),
                     "custom_domains" => web_proxy_config.custom_domains = Some(v.to_string()),
                     "subdomain" => web_proxy_config.subdomain = Some(v.to_string()),
+                    "use_encryption" => web_proxy_config.use_encryption = v == "true",
+                    "use_compression" => web_proxy_config.use_compression = v == "true",
+                    "plugin" => web_proxy_config.plugin = Some(v.to_string()),
+                    "proxy_protocol_version" => {
+                        let version = v.parse().with_context(|| format!("proxy [{}]", name))?;
+                        web_proxy_config.proxy_protocol_version = Some(version)
+                    }
+                    k if k.starts_with("plugin_") => {
+                        web_proxy_config
+                            .plugin_params
+                            .insert(k.to_string(), v.to_string());
+                    }
                     "type" => (),
                     _ => println!("invalid key {}", k),
                 }
             }
 
             self.web_configs.insert(name.to_string(), web_proxy_config);
+        } else if (stype.eq("stcp") || stype.eq("xtcp")) && section.get("role") == Some("visitor") {
+            let mut visitor_config = ClientVisitorConfig::new(stype.to_string());
+
+            for (k, v) in prop.iter() {
+                match k {
+                    "server_name" => visitor_config.server_name = v.to_string(),
+                    "sk" => visitor_config.sk = v.to_string(),
+                    "bind_addr" => visitor_config.bind_addr = v.to_string(),
+                    "bind_port" => visitor_config.bind_port = v.parse::<u16>().unwrap(),
+                    "use_encryption" => visitor_config.use_encryption = v == "true",
+                    "use_compression" => visitor_config.use_compression = v == "true",
+                    "type" | "role" => (),
+                    _ => println!("invalid key {}", k),
+                }
+            }
+
+            self.visitor_configs
+                .insert(name.to_string(), visitor_config);
+        } else if stype.eq("stcp") || stype.eq("xtcp") {
+            let mut stcp_proxy_config = ClientStcpConfig::new(stype.to_string());
+
+            for (k, v) in prop.iter() {
+                match k {
+                    "local_ip" => stcp_proxy_config.local_ip = v.to_string(),
+                    "local_port" => stcp_proxy_config.local_port = v.parse::<u16>().unwrap(),
+                    "sk" => stcp_proxy_config.sk = v.to_string(),
+                    "allow_users" => {
+                        stcp_proxy_config.allow_users =
+                            v.split(',').map(|u| u.trim().to_string()).collect()
+                    }
+                    "use_encryption" => stcp_proxy_config.use_encryption = v == "true",
+                    "use_compression" => stcp_proxy_config.use_compression = v == "true",
+                    "plugin" => stcp_proxy_config.plugin = Some(v.to_string()),
+                    "proxy_protocol_version" => {
+                        let version = v.parse().with_context(|| format!("proxy [{}]", name))?;
+                        stcp_proxy_config.proxy_protocol_version = Some(version)
+                    }
+                    k if k.starts_with("plugin_") => {
+                        stcp_proxy_config
+                            .plugin_params
+                            .insert(k.to_string(), v.to_string());
+                    }
+                    "type" | "role" => (),
+                    _ => println!("invalid key {}", k),
+                }
+            }
+
+            self.stcp_configs
+                .insert(name.to_string(), stcp_proxy_config);
         } else {
             println!("{} not support", stype);
         }
@@ -210,3 +578,25 @@ impl Config {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxy_protocol_version() {
+        let mut cfg = Config::new();
+        let content = "[web]\ntype = tcp\nlocal_port = 80\nproxy_protocol_version = v2\n";
+        cfg.load_config_str(content).unwrap();
+        let version = cfg.tcp_configs["web"].proxy_protocol_version;
+        assert_eq!(version, Some(proxy_protocol::Version::V2));
+
+        let mut cfg = Config::new();
+        let content = "[web]\ntype = tcp\nlocal_port = 80\nproxy_protocol_version = v3\n";
+        let e = cfg.load_config_str(content).err().unwrap();
+        assert_eq!(
+            format!("{:#}", e),
+            "proxy [web]: unsupported proxy_protocol_version v3"
+        );
+    }
+}
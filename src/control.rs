@@ -1,145 +1,245 @@
-use anyhow::{Error, Result};
+use anyhow::{anyhow, Context, Result};
 use futures::io::{AsyncRead as FAsyncRead, AsyncWrite as FAsyncWrite};
-use futures::stream::TryStreamExt;
+use futures::{stream::TryStreamExt, SinkExt, StreamExt};
 use futures_util::io::{AsyncReadExt, AsyncWriteExt};
-use std::{collections::HashMap, str};
-use tokio::io::{self, AsyncRead, AsyncWrite};
-use tokio::{net::TcpStream, time::timeout};
-use tokio_util::compat::{FuturesAsyncReadCompatExt, FuturesAsyncWriteCompatExt};
-use yamux::Stream;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt as _};
+use tokio::{
+    net::TcpStream,
+    time::{interval, timeout},
+};
+use tokio_util::{
+    codec::Framed,
+    compat::{Compat, FuturesAsyncReadCompatExt, FuturesAsyncWriteCompatExt},
+};
 
 use crate::{
-    config::{ClientTcpConfig, ClientWebConfig},
+    config::{ClientStcpConfig, ClientTcpConfig, ClientUdpConfig, ClientWebConfig},
     crypto::FrpCoder,
     msg::{
-        msg_header_decode, msg_header_encode, MsgHeader, NewProxy, NewWorkConn, ReqWorkConn,
-        StartWorkConn, TypeNewProxyResp, TypeNewWorkConn, TypeReqWorkConn, MSG_HEADER_SIZE,
+        read_msg, write_msg, Message, MsgCodec, NewProxy, NewProxyResp, NewWorkConn, Ping, Pong,
     },
+    proxy_protocol,
     service::Service,
+    stream,
+    transport::BoxedStream,
+    udp,
+    visitor::VisitorManager,
+    xtcp,
 };
 
+type MsgStream<'a, S> = Framed<Compat<&'a mut S>, MsgCodec>;
+
 #[derive(Debug, Clone)]
 pub struct Control {
     coder: FrpCoder,
     service: Service,
     send_proxy: bool,
+    last_pong: Instant,
 }
 
 impl Control {
     pub fn new(service: Service, iv: [u8; 16]) -> Self {
-        let mut coder = FrpCoder::new(service.cfg.auth_token(), iv);
+        let coder = FrpCoder::new(service.cfg.auth_token(), iv);
 
         Self {
             coder,
             service,
             send_proxy: false,
+            last_pong: Instant::now(),
         }
     }
 
-    pub async fn run(&mut self, main_stream: &mut Stream) -> Result<()> {
+    pub async fn run<S>(&mut self, main_stream: &mut S) -> Result<()>
+    where
+        S: FAsyncRead + FAsyncWrite + Unpin,
+    {
+        // frpc's own iv goes out in plain text ahead of the first encrypted message
+        main_stream.write_all(self.coder.iv()).await?;
+        let codec = MsgCodec::new(self.coder.clone(), self.coder.clone());
+        let mut framed = Framed::new(main_stream.compat(), codec);
+
+        self.send_proxy_conf(&mut framed).await?;
+        let _visitors = VisitorManager::start(&self.service, &self.service.cfg.visitor_configs);
+
+        // heartbeat_interval = 0 disables both ping and pong timeout checking
+        let heartbeat_interval = self.service.cfg.heartbeat_interval();
+        let heartbeat_timeout = Duration::from_secs(self.service.cfg.heartbeat_timeout() as u64);
+        let mut ping_ticker = interval(Duration::from_secs(heartbeat_interval.max(1) as u64));
+        let mut check_ticker = interval(Duration::from_secs(1));
+        self.last_pong = Instant::now();
+
         loop {
-            let mut buf = [0; 4096];
-            let n = main_stream.read(&mut buf).await?;
-            let mut plain_msg = buf[0..n].to_vec();
-            self.coder.decrypt(&mut plain_msg);
-            let hdr: [u8; MSG_HEADER_SIZE] = plain_msg[0..MSG_HEADER_SIZE]
-                .try_into()
-                .expect("slice with incorrect length");
-            let header: MsgHeader = msg_header_decode(&hdr);
-            assert_eq!(header.len as usize, n - MSG_HEADER_SIZE);
-            self.handle_msg(&header, &plain_msg[MSG_HEADER_SIZE..n])
-                .await;
-
-            self.send_proxy_conf(main_stream).await;
+            tokio::select! {
+                msg = framed.next() => {
+                    let msg = match msg {
+                        Some(msg) => msg?,
+                        None => return Err(anyhow!("main stream closed by server")),
+                    };
+                    self.handle_msg(msg).await?;
+                }
+                _ = ping_ticker.tick(), if heartbeat_interval > 0 => {
+                    framed.send(Message::Ping(Ping::new(&self.service.cfg))).await?;
+                }
+                _ = check_ticker.tick(), if heartbeat_interval > 0 => {
+                    if self.last_pong.elapsed() > heartbeat_timeout {
+                        return Err(anyhow!(
+                            "heartbeat timeout, no pong in {:?}",
+                            heartbeat_timeout
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    pub async fn handle_msg(&mut self, msg: Message) -> Result<()> {
+        match msg {
+            Message::NewProxyResp(resp) => self.handle_new_proxy_resp(resp).await,
+            Message::ReqWorkConn(_) => self.handle_req_work_conn().await,
+            Message::Pong(pong) => self.handle_pong(pong).await,
+            msg => {
+                println!("unsupported type {:?}", msg.msg_type());
+                Ok(())
+            }
         }
     }
 
-    pub async fn handle_msg(&mut self, header: &MsgHeader, msg: &[u8]) -> Result<()> {
-        match header.msg_type {
-            TypeNewProxyResp => self.handle_new_proxy_resp(msg).await,
-            TypeReqWorkConn => self.handle_req_work_conn().await,
-            _ => Err(anyhow::anyhow!("unsupported type {:?}", header)),
+    async fn handle_pong(&mut self, pong: Pong) -> Result<()> {
+        if !pong.error().is_empty() {
+            return Err(anyhow!("pong contains error: {}", pong.error()));
         }
+        self.last_pong = Instant::now();
+
+        Ok(())
     }
 
+    // frps asks for pool_count work connections after login and for one more
+    // each time it hands one out, so answering every request keeps the pool full.
+    // The dial runs in its own task to keep the control loop responsive.
     async fn handle_req_work_conn(&mut self) -> Result<()> {
-        let work_conn = NewWorkConn::new(self.service.run_id.clone(), &self.service.cfg);
-        let mut work_stream = self.service.main_ctl.open_stream().await.unwrap();
-        let frame = work_conn.to_string().into_bytes();
-        let hdr = MsgHeader::new(TypeNewWorkConn, frame.len() as u64);
-        work_stream
-            .write_all(&msg_header_encode(&hdr).to_vec())
-            .await;
-        work_stream.write_all(&frame).await;
-
-        let conf = self.service.get_conf().clone();
+        let service = self.service.clone();
         tokio::spawn(async move {
-            let mut msg_hdr = [0; MSG_HEADER_SIZE];
-            work_stream.read_exact(&mut msg_hdr).await;
-            let header: MsgHeader = msg_header_decode(&msg_hdr.try_into().unwrap());
-            let mut msg = vec![0; header.len as usize];
-            work_stream.read_exact(&mut msg).await;
-            let resp = String::from_utf8_lossy(&msg);
-            let start_work_conn: StartWorkConn = serde_json::from_str(&resp).unwrap();
-
-            let prxy = conf.get_proxy(&start_work_conn.proxy_name).unwrap();
-            let mut local_stream =
-                TcpStream::connect(format!("{}:{}", prxy.server_addr, prxy.server_port)).await;
-
-            proxy(local_stream.unwrap(), work_stream).await;
+            let work_conn = NewWorkConn::new(service.run_id.clone(), &service.cfg);
+            let res = async {
+                let mut work_stream = service.open_stream().await?;
+                write_msg(&mut work_stream, &Message::NewWorkConn(work_conn)).await?;
+                handle_work_conn(work_stream, service).await
+            };
+            if let Err(e) = res.await {
+                println!("work connection error {:#}", e);
+            }
         });
 
         Ok(())
     }
 
-    async fn handle_new_proxy_resp(&mut self, msg: &[u8]) -> Result<()> {
-        let res = str::from_utf8(msg).unwrap();
-        println!("new proxy response {}", res);
+    async fn handle_new_proxy_resp(&mut self, resp: NewProxyResp) -> Result<()> {
+        if !resp.error.is_empty() {
+            println!("start proxy [{}] error: {}", resp.proxy_name, resp.error);
+        } else {
+            println!(
+                "start proxy [{}] success, remote address {}",
+                resp.proxy_name, resp.remote_addr
+            );
+        }
 
         Ok(())
     }
 
-    async fn send_proxy_conf(&mut self, main_stream: &mut Stream) -> Result<()> {
+    async fn send_proxy_conf<S>(&mut self, main_stream: &mut MsgStream<'_, S>) -> Result<()>
+    where
+        S: FAsyncRead + FAsyncWrite + Unpin,
+    {
         if self.send_proxy {
             println!("already send proxy conf");
             return Ok(());
         }
 
-        let iv = self.coder.iv();
-        main_stream.write_all(iv).await?;
-
-        let mut cfg = self.service.get_conf().clone();
+        let cfg = self.service.get_conf().clone();
         self.send_tcp_proxy_conf(main_stream, &cfg.tcp_configs)
             .await?;
+        self.send_udp_proxy_conf(main_stream, &cfg.udp_configs)
+            .await?;
         self.send_web_proxy_conf(main_stream, &cfg.web_configs)
             .await?;
+        self.send_stcp_proxy_conf(main_stream, &cfg.stcp_configs)
+            .await?;
 
         self.send_proxy = true;
 
         Ok(())
     }
 
-    async fn send_tcp_proxy_conf(
+    async fn send_tcp_proxy_conf<S: FAsyncRead + FAsyncWrite + Unpin>(
         &mut self,
-        main_stream: &mut Stream,
+        main_stream: &mut MsgStream<'_, S>,
         configs: &HashMap<String, ClientTcpConfig>,
     ) -> Result<()> {
         for (proxy_name, tcp_config) in configs {
             let mut new_proxy = NewProxy::new(&proxy_name, &tcp_config.service_type);
+            new_proxy.set_use_encryption(tcp_config.use_encryption);
+            new_proxy.set_use_compression(tcp_config.use_compression);
             new_proxy.set_remote_port(tcp_config.remote_port);
-            new_proxy.send_msg(main_stream, &mut self.coder).await?;
+            main_stream
+                .send(Message::NewProxy(Box::new(new_proxy)))
+                .await?;
         }
 
         Ok(())
     }
 
-    async fn send_web_proxy_conf(
+    async fn send_udp_proxy_conf<S: FAsyncRead + FAsyncWrite + Unpin>(
         &mut self,
-        main_stream: &mut Stream,
+        main_stream: &mut MsgStream<'_, S>,
+        configs: &HashMap<String, ClientUdpConfig>,
+    ) -> Result<()> {
+        for (proxy_name, udp_config) in configs {
+            let mut new_proxy = NewProxy::new(&proxy_name, &udp_config.service_type);
+            new_proxy.set_use_encryption(udp_config.use_encryption);
+            new_proxy.set_use_compression(udp_config.use_compression);
+            new_proxy.set_remote_port(udp_config.remote_port);
+            main_stream
+                .send(Message::NewProxy(Box::new(new_proxy)))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn send_stcp_proxy_conf<S: FAsyncRead + FAsyncWrite + Unpin>(
+        &mut self,
+        main_stream: &mut MsgStream<'_, S>,
+        configs: &HashMap<String, ClientStcpConfig>,
+    ) -> Result<()> {
+        for (proxy_name, stcp_config) in configs {
+            let mut new_proxy = NewProxy::new(&proxy_name, &stcp_config.service_type);
+            new_proxy.set_use_encryption(stcp_config.use_encryption);
+            new_proxy.set_use_compression(stcp_config.use_compression);
+            new_proxy.set_sk(&stcp_config.sk);
+            if !stcp_config.allow_users.is_empty() {
+                new_proxy.set_allow_users(&stcp_config.allow_users);
+            }
+            main_stream
+                .send(Message::NewProxy(Box::new(new_proxy)))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn send_web_proxy_conf<S: FAsyncRead + FAsyncWrite + Unpin>(
+        &mut self,
+        main_stream: &mut MsgStream<'_, S>,
         configs: &HashMap<String, ClientWebConfig>,
     ) -> Result<()> {
         for (proxy_name, web_config) in configs {
             let mut new_proxy = NewProxy::new(&proxy_name, &web_config.service_type);
+            new_proxy.set_use_encryption(web_config.use_encryption);
+            new_proxy.set_use_compression(web_config.use_compression);
             if !web_config.custom_domains.is_none() {
                 let mut domains = Vec::new();
                 let custom_domain = web_config.custom_domains.as_ref().unwrap();
@@ -150,13 +250,71 @@ impl Control {
                 new_proxy.set_subdomain(web_config.subdomain.as_ref().unwrap());
             }
 
-            new_proxy.send_msg(main_stream, &mut self.coder).await?;
+            main_stream
+                .send(Message::NewProxy(Box::new(new_proxy)))
+                .await?;
         }
 
         Ok(())
     }
 }
 
+async fn handle_work_conn(mut work_stream: BoxedStream, service: Service) -> Result<()> {
+    let start_work_conn = match read_msg(&mut work_stream).await? {
+        Message::StartWorkConn(start_work_conn) => start_work_conn,
+        msg => return Err(anyhow!("expect start work conn, got {:?}", msg.msg_type())),
+    };
+    if !start_work_conn.error().is_empty() {
+        return Err(anyhow!(
+            "start work conn error: {}",
+            start_work_conn.error()
+        ));
+    }
+
+    let conf = &service.cfg;
+    let proxy_name = &start_work_conn.proxy_name;
+    let prxy = conf.get_proxy(proxy_name)?;
+    let local_addr = format!("{}:{}", prxy.server_addr, prxy.server_port);
+    let work_stream = if prxy.proxy_type == "xtcp" {
+        // xtcp traffic never passes frps, both peers encrypt with the secret key
+        let key = prxy
+            .use_encryption
+            .then(|| conf.stcp_configs[proxy_name].sk.clone());
+        xtcp::accept_xtcp_work_conn(
+            work_stream,
+            &service,
+            proxy_name,
+            key.as_deref(),
+            prxy.use_compression,
+        )
+        .await?
+    } else {
+        let key = prxy.use_encryption.then(|| conf.auth_token());
+        stream::with_options(work_stream, key, prxy.use_compression)
+    };
+    if prxy.proxy_type == "udp" {
+        return udp::handle_udp_work_conn(work_stream, &local_addr, conf.udp_packet_size()).await;
+    }
+
+    if let Some(plugin) = service.plugins.get(proxy_name) {
+        return plugin
+            .handle(work_stream, start_work_conn)
+            .await
+            .with_context(|| format!("plugin {}", plugin.name()));
+    }
+
+    let mut local_stream = TcpStream::connect(local_addr).await?;
+    if let Some(version) = prxy.proxy_protocol_version {
+        // tell the local service who the user really is
+        let header = proxy_protocol::header(version, &start_work_conn);
+        local_stream.write_all(&header).await?;
+    }
+
+    proxy(local_stream, work_stream).await?;
+
+    Ok(())
+}
+
 pub async fn proxy<S1, S2>(stream1: S1, stream2: S2) -> io::Result<()>
 where
     S1: AsyncRead + AsyncWrite + Unpin,
@@ -172,3 +330,142 @@ where
     }?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, msg::StartWorkConn};
+    use std::collections::HashSet;
+    use tokio::io::{duplex, AsyncReadExt as _, DuplexStream};
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    const CONFIG: &str = "
+[common]
+auth_token = secret
+heartbeat_interval = 1
+heartbeat_timeout = 2
+
+[ssh]
+type = tcp
+local_port = 22
+remote_port = 6000
+
+[dns]
+type = udp
+local_port = 53
+remote_port = 6001
+";
+
+    fn control(content: &str) -> Control {
+        let mut cfg = Config::new();
+        cfg.load_config_str(content).unwrap();
+        Control::new(Service::with_streams(cfg, vec![]), [3; 16])
+    }
+
+    // reads frpc's iv and sets up the server end of the encrypted message stream
+    async fn frps_end(mut stream: DuplexStream) -> Framed<DuplexStream, MsgCodec> {
+        let mut iv = [0; 16];
+        stream.read_exact(&mut iv).await.unwrap();
+        assert_eq!(iv, [3; 16]);
+        let coder = FrpCoder::new("secret", iv);
+        Framed::new(stream, MsgCodec::new(coder.clone(), coder))
+    }
+
+    #[tokio::test]
+    async fn run_registers_every_proxy() {
+        let (client_end, server_end) = duplex(65536);
+        let mut ctl = control(CONFIG);
+        let client = tokio::spawn(async move { ctl.run(&mut client_end.compat()).await });
+
+        let mut framed = frps_end(server_end).await;
+        let mut proxies = HashSet::new();
+        while proxies.len() < 2 {
+            match framed.next().await.unwrap().unwrap() {
+                Message::NewProxy(new_proxy) => {
+                    let new_proxy = serde_json::to_value(new_proxy).unwrap();
+                    proxies.insert((
+                        new_proxy["proxy_name"].as_str().unwrap().to_string(),
+                        new_proxy["proxy_type"].as_str().unwrap().to_string(),
+                        new_proxy["remote_port"].as_u64().unwrap(),
+                    ));
+                }
+                Message::Ping(_) => (),
+                msg => panic!("unexpected {:?}", msg.msg_type()),
+            }
+        }
+        let expected = [
+            ("ssh".to_string(), "tcp".to_string(), 6000),
+            ("dns".to_string(), "udp".to_string(), 6001),
+        ];
+        assert_eq!(proxies, expected.into_iter().collect());
+        client.abort();
+    }
+
+    #[tokio::test]
+    async fn run_fails_without_pong() {
+        let (client_end, server_end) = duplex(65536);
+        let mut ctl = control(CONFIG);
+        let client = tokio::spawn(async move { ctl.run(&mut client_end.compat()).await });
+
+        // answer nothing, only drain what frpc sends
+        let mut framed = frps_end(server_end).await;
+        let drain = tokio::spawn(async move { while let Some(Ok(_)) = framed.next().await {} });
+
+        let res = timeout(Duration::from_secs(5), client).await.unwrap();
+        let e = res.unwrap().err().unwrap();
+        assert!(e.to_string().contains("heartbeat timeout"), "{}", e);
+        drain.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stcp_proxy_registers_sk_and_serves_work_conns() {
+        let local = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let content = format!(
+            "[common]\nauth_token = secret\n\n[secret_ssh]\ntype = stcp\nsk = abcdefg\n\
+             allow_users = alice, bob\nlocal_port = {}\nuse_encryption = true\n",
+            local.local_addr().unwrap().port()
+        );
+        let (client_end, server_end) = duplex(65536);
+        let mut ctl = control(&content);
+        let service = ctl.service.clone();
+        let client = tokio::spawn(async move { ctl.run(&mut client_end.compat()).await });
+
+        let mut framed = frps_end(server_end).await;
+        let new_proxy = loop {
+            match framed.next().await.unwrap().unwrap() {
+                Message::NewProxy(new_proxy) => break serde_json::to_value(new_proxy).unwrap(),
+                Message::Ping(_) => (),
+                msg => panic!("unexpected {:?}", msg.msg_type()),
+            }
+        };
+        assert_eq!(new_proxy["proxy_name"], "secret_ssh");
+        assert_eq!(new_proxy["proxy_type"], "stcp");
+        assert_eq!(new_proxy["sk"], "abcdefg");
+        assert_eq!(
+            new_proxy["allow_users"],
+            serde_json::json!(["alice", "bob"])
+        );
+        assert_eq!(new_proxy["use_encryption"], true);
+        client.abort();
+
+        // frps relays the visitor re-encrypted with the token, not sk
+        let (work_end, frps_work_end) = duplex(65536);
+        let work = tokio::spawn(handle_work_conn(Box::new(work_end.compat()), service));
+        let mut frps_work_end = frps_work_end.compat();
+        let start: StartWorkConn =
+            serde_json::from_value(serde_json::json!({ "proxy_name": "secret_ssh" })).unwrap();
+        write_msg(&mut frps_work_end, &Message::StartWorkConn(start))
+            .await
+            .unwrap();
+        let mut frps_work_end = stream::with_options(frps_work_end, Some("secret"), false);
+        frps_work_end.write_all(b"ping").await.unwrap();
+        frps_work_end.flush().await.unwrap();
+
+        let (mut local_conn, _) = local.accept().await.unwrap();
+        let mut ping = [0; 4];
+        local_conn.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+        drop(local_conn);
+        work.await.unwrap().unwrap();
+    }
+}
@@ -46,7 +46,7 @@ impl FrpCoder {
         &self.iv
     }
 
-    pub fn encypt(&mut self, buf: &mut Vec<u8>) -> Result<()> {
+    pub fn encypt(&mut self, buf: &mut [u8]) -> Result<()> {
         let (iv, pos) = self.enc.get_state();
         let cipher = Aes128::new_from_slice(self.key()).unwrap();
         self.enc = Aes128CfbEnc::from_state(cipher, iv, pos);
@@ -56,7 +56,7 @@ impl FrpCoder {
         Ok(())
     } 
 
-    pub fn decrypt(&mut self, buf: &mut Vec<u8>) -> Result<()> {
+    pub fn decrypt(&mut self, buf: &mut [u8]) -> Result<()> {
         let (iv, pos) = self.dec.get_state();
         let cipher = Aes128::new_from_slice(self.key()).unwrap();
         self.dec = Aes128CfbDec::from_state(cipher, iv, pos);
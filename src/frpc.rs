@@ -4,7 +4,7 @@ use log::{info, trace};
 use std::process::ExitCode;
 
 use crate::config::Config;
-use crate::service::Service;
+use crate::service::Supervisor;
 
 pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
     app = app.arg(
@@ -21,8 +21,8 @@ pub fn define_command_line_options(mut app: Command<'_>) -> Command<'_> {
 
 #[tokio::main]
 async fn start_service(config: Config) -> Result<()> {
-    let mut service = Service::new(config).await?;
-    service.run().await?;
+    let mut supervisor = Supervisor::new(config)?;
+    supervisor.run().await?;
 
     Ok(())
 }
@@ -30,9 +30,15 @@ async fn start_service(config: Config) -> Result<()> {
 pub fn main(matches: &ArgMatches) -> ExitCode {
     let config_file = matches.value_of("config").unwrap();
     let mut client_config = Config::new();
-    client_config.load_config(config_file);
-
-    start_service(client_config);
+    if let Err(e) = client_config.load_config(config_file) {
+        println!("load config {} error: {:#}", config_file, e);
+        return ExitCode::FAILURE;
+    }
+
+    if let Err(e) = start_service(client_config) {
+        println!("app exit {:#}", e);
+        return ExitCode::FAILURE;
+    }
 
     ExitCode::SUCCESS
 }
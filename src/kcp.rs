@@ -0,0 +1,1189 @@
+//! A KCP implementation speaking the same wire format as kcp-go, which frp
+//! uses for xtcp sessions and for its kcp transport.
+//!
+//! `Kcp` is a straight port of the ikcp state machine. `KcpStream` drives it
+//! over a connected `UdpSocket` behind kcp-go's FEC layer, using the 10 data /
+//! 3 parity Reed-Solomon shards frp configures.
+
+use anyhow::{anyhow, Result};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    sync::mpsc::{self, Receiver, Sender},
+    time::interval,
+};
+use tokio_util::sync::PollSender;
+
+const KCP_RTO_NDL: u32 = 30;
+const KCP_RTO_MIN: u32 = 100;
+const KCP_RTO_DEF: u32 = 200;
+const KCP_RTO_MAX: u32 = 60000;
+const KCP_CMD_PUSH: u8 = 81;
+const KCP_CMD_ACK: u8 = 82;
+const KCP_CMD_WASK: u8 = 83;
+const KCP_CMD_WINS: u8 = 84;
+const KCP_ASK_SEND: u32 = 1;
+const KCP_ASK_TELL: u32 = 2;
+const KCP_WND_SND: u32 = 32;
+const KCP_WND_RCV: u32 = 128;
+const KCP_MTU_DEF: usize = 1400;
+const KCP_INTERVAL: u32 = 100;
+const KCP_OVERHEAD: usize = 24;
+const KCP_DEADLINK: u32 = 20;
+const KCP_THRESH_INIT: u32 = 2;
+const KCP_THRESH_MIN: u32 = 2;
+const KCP_PROBE_INIT: u32 = 7000;
+const KCP_PROBE_LIMIT: u32 = 120000;
+const KCP_FASTACK_LIMIT: u32 = 5;
+
+const FEC_DATA_SHARDS: u32 = 10;
+const FEC_PARITY_SHARDS: u32 = 3;
+const FEC_HEADER_SIZE: usize = 6;
+const FEC_HEADER_SIZE_PLUS2: usize = FEC_HEADER_SIZE + 2;
+const FEC_TYPE_DATA: u16 = 0xf1;
+const FEC_TYPE_PARITY: u16 = 0xf2;
+// groups kept open for late shards, older ones are given up
+const FEC_RX_GROUPS: usize = 3;
+
+// a session that hears nothing from its peer for this long is considered dead
+const KCP_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const KCP_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+// how long queued data may still be retransmitted after the stream is shut down
+const KCP_LINGER: Duration = Duration::from_secs(5);
+const KCP_MAX_WRITE_CHUNK: usize = 32 * 1024;
+const KCP_CHANNEL_SIZE: usize = 128;
+
+fn timediff(later: u32, earlier: u32) -> i32 {
+    later.wrapping_sub(earlier) as i32
+}
+
+#[derive(Default, Clone)]
+struct Segment {
+    conv: u32,
+    cmd: u8,
+    frg: u8,
+    wnd: u16,
+    ts: u32,
+    sn: u32,
+    una: u32,
+    resendts: u32,
+    rto: u32,
+    fastack: u32,
+    xmit: u32,
+    data: Vec<u8>,
+}
+
+impl Segment {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.conv.to_le_bytes());
+        buf.push(self.cmd);
+        buf.push(self.frg);
+        buf.extend_from_slice(&self.wnd.to_le_bytes());
+        buf.extend_from_slice(&self.ts.to_le_bytes());
+        buf.extend_from_slice(&self.sn.to_le_bytes());
+        buf.extend_from_slice(&self.una.to_le_bytes());
+        buf.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.data);
+    }
+}
+
+/// The KCP protocol state machine, ported from ikcp.c.
+pub struct Kcp {
+    conv: u32,
+    mtu: usize,
+    mss: usize,
+    dead: bool,
+    snd_una: u32,
+    snd_nxt: u32,
+    rcv_nxt: u32,
+    ssthresh: u32,
+    rx_rttval: u32,
+    rx_srtt: u32,
+    rx_rto: u32,
+    rx_minrto: u32,
+    snd_wnd: u32,
+    rcv_wnd: u32,
+    rmt_wnd: u32,
+    cwnd: u32,
+    probe: u32,
+    current: u32,
+    interval: u32,
+    ts_flush: u32,
+    nodelay: u32,
+    updated: bool,
+    ts_probe: u32,
+    probe_wait: u32,
+    dead_link: u32,
+    incr: u32,
+    snd_queue: VecDeque<Segment>,
+    rcv_queue: VecDeque<Segment>,
+    snd_buf: VecDeque<Segment>,
+    rcv_buf: VecDeque<Segment>,
+    acklist: Vec<(u32, u32)>,
+    fastresend: u32,
+    fastlimit: u32,
+    nocwnd: bool,
+    stream: bool,
+    buffer: Vec<u8>,
+    output: VecDeque<Vec<u8>>,
+}
+
+impl Kcp {
+    pub fn new(conv: u32) -> Self {
+        Self {
+            conv,
+            mtu: KCP_MTU_DEF,
+            mss: KCP_MTU_DEF - KCP_OVERHEAD,
+            dead: false,
+            snd_una: 0,
+            snd_nxt: 0,
+            rcv_nxt: 0,
+            ssthresh: KCP_THRESH_INIT,
+            rx_rttval: 0,
+            rx_srtt: 0,
+            rx_rto: KCP_RTO_DEF,
+            rx_minrto: KCP_RTO_MIN,
+            snd_wnd: KCP_WND_SND,
+            rcv_wnd: KCP_WND_RCV,
+            rmt_wnd: KCP_WND_RCV,
+            cwnd: 0,
+            probe: 0,
+            current: 0,
+            interval: KCP_INTERVAL,
+            ts_flush: KCP_INTERVAL,
+            nodelay: 0,
+            updated: false,
+            ts_probe: 0,
+            probe_wait: 0,
+            dead_link: KCP_DEADLINK,
+            incr: 0,
+            snd_queue: VecDeque::new(),
+            rcv_queue: VecDeque::new(),
+            snd_buf: VecDeque::new(),
+            rcv_buf: VecDeque::new(),
+            acklist: Vec::new(),
+            fastresend: 0,
+            fastlimit: KCP_FASTACK_LIMIT,
+            nocwnd: false,
+            stream: false,
+            buffer: Vec::with_capacity(KCP_MTU_DEF),
+            output: VecDeque::new(),
+        }
+    }
+
+    /// Sets the mtu of a whole udp packet, `reserved` bytes of which are
+    /// taken by headers added outside of KCP.
+    pub fn set_mtu(&mut self, mtu: usize, reserved: usize) {
+        self.mtu = mtu - reserved;
+        self.mss = self.mtu - KCP_OVERHEAD;
+    }
+
+    pub fn set_nodelay(&mut self, nodelay: u32, interval: u32, resend: u32, nocwnd: bool) {
+        self.nodelay = nodelay;
+        self.rx_minrto = if nodelay > 0 {
+            KCP_RTO_NDL
+        } else {
+            KCP_RTO_MIN
+        };
+        self.interval = interval.clamp(10, 5000);
+        self.fastresend = resend;
+        self.nocwnd = nocwnd;
+    }
+
+    pub fn set_wndsize(&mut self, snd_wnd: u32, rcv_wnd: u32) {
+        self.snd_wnd = snd_wnd;
+        self.rcv_wnd = rcv_wnd.max(KCP_WND_RCV);
+    }
+
+    pub fn set_stream(&mut self, stream: bool) {
+        self.stream = stream;
+    }
+
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    pub fn snd_wnd(&self) -> u32 {
+        self.snd_wnd
+    }
+
+    pub fn wait_snd(&self) -> usize {
+        self.snd_buf.len() + self.snd_queue.len()
+    }
+
+    /// Too many retransmissions of a single segment, the peer is gone.
+    pub fn is_dead(&self) -> bool {
+        self.dead
+    }
+
+    /// Makes the next flush ask the peer for its window size. The answer keeps
+    /// an otherwise idle session, and the NAT mappings under it, alive.
+    pub fn keepalive(&mut self) {
+        self.probe |= KCP_ASK_SEND;
+    }
+
+    /// Takes the next packet produced by `update`.
+    pub fn pop_output(&mut self) -> Option<Vec<u8>> {
+        self.output.pop_front()
+    }
+
+    fn peek_size(&self) -> Option<usize> {
+        let seg = self.rcv_queue.front()?;
+        if seg.frg == 0 {
+            return Some(seg.data.len());
+        }
+        if self.rcv_queue.len() < seg.frg as usize + 1 {
+            return None;
+        }
+
+        let mut len = 0;
+        for seg in &self.rcv_queue {
+            len += seg.data.len();
+            if seg.frg == 0 {
+                break;
+            }
+        }
+
+        Some(len)
+    }
+
+    /// Returns the next complete message, in stream mode just the next
+    /// chunk of in-order data.
+    pub fn recv(&mut self) -> Option<Vec<u8>> {
+        let size = self.peek_size()?;
+        let recover = self.rcv_queue.len() as u32 >= self.rcv_wnd;
+
+        let mut data = Vec::with_capacity(size);
+        while let Some(seg) = self.rcv_queue.pop_front() {
+            data.extend_from_slice(&seg.data);
+            if seg.frg == 0 {
+                break;
+            }
+        }
+
+        self.move_rcv_buf();
+
+        // tell the peer our window is open again
+        if (self.rcv_queue.len() as u32) < self.rcv_wnd && recover {
+            self.probe |= KCP_ASK_TELL;
+        }
+
+        Some(data)
+    }
+
+    pub fn send(&mut self, mut buf: &[u8]) {
+        if self.stream {
+            if let Some(old) = self.snd_queue.back_mut() {
+                if old.data.len() < self.mss {
+                    let extend = buf.len().min(self.mss - old.data.len());
+                    old.data.extend_from_slice(&buf[..extend]);
+                    old.frg = 0;
+                    buf = &buf[extend..];
+                }
+            }
+            if buf.is_empty() {
+                return;
+            }
+        }
+
+        let count = buf.len().div_ceil(self.mss).max(1);
+        for i in 0..count {
+            let size = buf.len().min(self.mss);
+            let frg = if self.stream {
+                0
+            } else {
+                (count - i - 1) as u8
+            };
+            self.snd_queue.push_back(Segment {
+                frg,
+                data: buf[..size].to_vec(),
+                ..Default::default()
+            });
+            buf = &buf[size..];
+        }
+    }
+
+    fn update_ack(&mut self, rtt: u32) {
+        if self.rx_srtt == 0 {
+            self.rx_srtt = rtt;
+            self.rx_rttval = rtt / 2;
+        } else {
+            let delta = rtt.abs_diff(self.rx_srtt);
+            self.rx_rttval = (3 * self.rx_rttval + delta) / 4;
+            self.rx_srtt = ((7 * self.rx_srtt + rtt) / 8).max(1);
+        }
+        let rto = self.rx_srtt + self.interval.max(4 * self.rx_rttval);
+        self.rx_rto = rto.clamp(self.rx_minrto, KCP_RTO_MAX);
+    }
+
+    fn shrink_buf(&mut self) {
+        self.snd_una = match self.snd_buf.front() {
+            Some(seg) => seg.sn,
+            None => self.snd_nxt,
+        };
+    }
+
+    fn parse_ack(&mut self, sn: u32) {
+        if timediff(sn, self.snd_una) < 0 || timediff(sn, self.snd_nxt) >= 0 {
+            return;
+        }
+        for i in 0..self.snd_buf.len() {
+            let seg_sn = self.snd_buf[i].sn;
+            if sn == seg_sn {
+                self.snd_buf.remove(i);
+                break;
+            }
+            if timediff(sn, seg_sn) < 0 {
+                break;
+            }
+        }
+    }
+
+    fn parse_una(&mut self, una: u32) {
+        while let Some(seg) = self.snd_buf.front() {
+            if timediff(una, seg.sn) > 0 {
+                self.snd_buf.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn parse_fastack(&mut self, sn: u32) {
+        if timediff(sn, self.snd_una) < 0 || timediff(sn, self.snd_nxt) >= 0 {
+            return;
+        }
+        for seg in self.snd_buf.iter_mut() {
+            if timediff(sn, seg.sn) < 0 {
+                break;
+            } else if sn != seg.sn {
+                seg.fastack += 1;
+            }
+        }
+    }
+
+    fn parse_data(&mut self, newseg: Segment) {
+        let sn = newseg.sn;
+        if timediff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) >= 0
+            || timediff(sn, self.rcv_nxt) < 0
+        {
+            return;
+        }
+
+        let mut insert_at = 0;
+        let mut repeat = false;
+        for i in (0..self.rcv_buf.len()).rev() {
+            let seg_sn = self.rcv_buf[i].sn;
+            if seg_sn == sn {
+                repeat = true;
+                break;
+            }
+            if timediff(sn, seg_sn) > 0 {
+                insert_at = i + 1;
+                break;
+            }
+        }
+        if !repeat {
+            self.rcv_buf.insert(insert_at, newseg);
+        }
+
+        self.move_rcv_buf();
+    }
+
+    fn move_rcv_buf(&mut self) {
+        while let Some(seg) = self.rcv_buf.front() {
+            if seg.sn == self.rcv_nxt && (self.rcv_queue.len() as u32) < self.rcv_wnd {
+                let seg = self.rcv_buf.pop_front().unwrap();
+                self.rcv_queue.push_back(seg);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Feeds one packet received from the peer.
+    pub fn input(&mut self, mut data: &[u8]) -> Result<()> {
+        if data.len() < KCP_OVERHEAD {
+            return Err(anyhow!("kcp packet too short"));
+        }
+
+        let prev_una = self.snd_una;
+        let mut maxack = None;
+
+        while data.len() >= KCP_OVERHEAD {
+            let u32_at = |pos: usize| u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+            let conv = u32_at(0);
+            if conv != self.conv {
+                return Err(anyhow!("kcp conv mismatch {} != {}", conv, self.conv));
+            }
+            let cmd = data[4];
+            let frg = data[5];
+            let wnd = u16::from_le_bytes([data[6], data[7]]);
+            let ts = u32_at(8);
+            let sn = u32_at(12);
+            let una = u32_at(16);
+            let len = u32_at(20) as usize;
+            data = &data[KCP_OVERHEAD..];
+            if data.len() < len {
+                return Err(anyhow!("kcp segment truncated"));
+            }
+
+            self.rmt_wnd = wnd as u32;
+            self.parse_una(una);
+            self.shrink_buf();
+
+            match cmd {
+                KCP_CMD_ACK => {
+                    if timediff(self.current, ts) >= 0 {
+                        self.update_ack(timediff(self.current, ts) as u32);
+                    }
+                    self.parse_ack(sn);
+                    self.shrink_buf();
+                    maxack = match maxack {
+                        Some(max) if timediff(sn, max) <= 0 => Some(max),
+                        _ => Some(sn),
+                    };
+                }
+                KCP_CMD_PUSH => {
+                    if timediff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) < 0 {
+                        self.acklist.push((sn, ts));
+                        if timediff(sn, self.rcv_nxt) >= 0 {
+                            self.parse_data(Segment {
+                                conv,
+                                cmd,
+                                frg,
+                                wnd,
+                                ts,
+                                sn,
+                                una,
+                                data: data[..len].to_vec(),
+                                ..Default::default()
+                            });
+                        }
+                    }
+                }
+                KCP_CMD_WASK => self.probe |= KCP_ASK_TELL,
+                KCP_CMD_WINS => (),
+                _ => return Err(anyhow!("unknown kcp command {}", cmd)),
+            }
+
+            data = &data[len..];
+        }
+
+        if let Some(maxack) = maxack {
+            self.parse_fastack(maxack);
+        }
+
+        if timediff(self.snd_una, prev_una) > 0 && self.cwnd < self.rmt_wnd {
+            let mss = self.mss as u32;
+            if self.cwnd < self.ssthresh {
+                self.cwnd += 1;
+                self.incr += mss;
+            } else {
+                if self.incr < mss {
+                    self.incr = mss;
+                }
+                self.incr += (mss * mss) / self.incr + (mss / 16);
+                if (self.cwnd + 1) * mss <= self.incr {
+                    self.cwnd = (self.incr + mss - 1) / mss.max(1);
+                }
+            }
+            if self.cwnd > self.rmt_wnd {
+                self.cwnd = self.rmt_wnd;
+                self.incr = self.rmt_wnd * mss;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn wnd_unused(&self) -> u16 {
+        let queued = self.rcv_queue.len() as u32;
+        if queued < self.rcv_wnd {
+            (self.rcv_wnd - queued) as u16
+        } else {
+            0
+        }
+    }
+
+    fn push_segment(&mut self, seg: &Segment) {
+        if self.buffer.len() + KCP_OVERHEAD + seg.data.len() > self.mtu {
+            self.flush_buffer();
+        }
+        seg.encode(&mut self.buffer);
+    }
+
+    fn flush_buffer(&mut self) {
+        if !self.buffer.is_empty() {
+            let packet = std::mem::replace(&mut self.buffer, Vec::with_capacity(self.mtu));
+            self.output.push_back(packet);
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.updated {
+            return;
+        }
+        let current = self.current;
+        let mut change = false;
+        let mut lost = false;
+
+        let mut seg = Segment {
+            conv: self.conv,
+            cmd: KCP_CMD_ACK,
+            wnd: self.wnd_unused(),
+            una: self.rcv_nxt,
+            ..Default::default()
+        };
+
+        let acklist = std::mem::take(&mut self.acklist);
+        for (sn, ts) in acklist {
+            seg.sn = sn;
+            seg.ts = ts;
+            self.push_segment(&seg);
+        }
+
+        // probe window size if the remote window is closed
+        if self.rmt_wnd == 0 {
+            if self.probe_wait == 0 {
+                self.probe_wait = KCP_PROBE_INIT;
+                self.ts_probe = self.current.wrapping_add(self.probe_wait);
+            } else if timediff(self.current, self.ts_probe) >= 0 {
+                self.probe_wait = self.probe_wait.max(KCP_PROBE_INIT);
+                self.probe_wait += self.probe_wait / 2;
+                self.probe_wait = self.probe_wait.min(KCP_PROBE_LIMIT);
+                self.ts_probe = self.current.wrapping_add(self.probe_wait);
+                self.probe |= KCP_ASK_SEND;
+            }
+        } else {
+            self.ts_probe = 0;
+            self.probe_wait = 0;
+        }
+
+        seg.sn = 0;
+        seg.ts = 0;
+        if self.probe & KCP_ASK_SEND != 0 {
+            seg.cmd = KCP_CMD_WASK;
+            self.push_segment(&seg);
+        }
+        if self.probe & KCP_ASK_TELL != 0 {
+            seg.cmd = KCP_CMD_WINS;
+            self.push_segment(&seg);
+        }
+        self.probe = 0;
+
+        let mut cwnd = self.snd_wnd.min(self.rmt_wnd);
+        if !self.nocwnd {
+            cwnd = cwnd.min(self.cwnd);
+        }
+
+        while timediff(self.snd_nxt, self.snd_una.wrapping_add(cwnd)) < 0 {
+            let mut newseg = match self.snd_queue.pop_front() {
+                Some(newseg) => newseg,
+                None => break,
+            };
+            newseg.conv = self.conv;
+            newseg.cmd = KCP_CMD_PUSH;
+            newseg.wnd = seg.wnd;
+            newseg.ts = current;
+            newseg.sn = self.snd_nxt;
+            newseg.una = self.rcv_nxt;
+            newseg.resendts = current;
+            newseg.rto = self.rx_rto;
+            newseg.fastack = 0;
+            newseg.xmit = 0;
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.snd_buf.push_back(newseg);
+        }
+
+        let resent = if self.fastresend > 0 {
+            self.fastresend
+        } else {
+            u32::MAX
+        };
+        let rtomin = if self.nodelay == 0 {
+            self.rx_rto >> 3
+        } else {
+            0
+        };
+
+        for i in 0..self.snd_buf.len() {
+            let mut needsend = false;
+            {
+                let segment = &mut self.snd_buf[i];
+                if segment.xmit == 0 {
+                    needsend = true;
+                    segment.xmit += 1;
+                    segment.rto = self.rx_rto;
+                    segment.resendts = current.wrapping_add(segment.rto + rtomin);
+                } else if timediff(current, segment.resendts) >= 0 {
+                    needsend = true;
+                    segment.xmit += 1;
+                    if self.nodelay == 0 {
+                        segment.rto += segment.rto.max(self.rx_rto);
+                    } else {
+                        let step = if self.nodelay < 2 {
+                            segment.rto
+                        } else {
+                            self.rx_rto
+                        };
+                        segment.rto += step / 2;
+                    }
+                    segment.resendts = current.wrapping_add(segment.rto);
+                    lost = true;
+                } else if segment.fastack >= resent
+                    && (segment.xmit <= self.fastlimit || self.fastlimit == 0)
+                {
+                    needsend = true;
+                    segment.xmit += 1;
+                    segment.fastack = 0;
+                    segment.resendts = current.wrapping_add(segment.rto);
+                    change = true;
+                }
+
+                if needsend {
+                    segment.ts = current;
+                    segment.wnd = seg.wnd;
+                    segment.una = self.rcv_nxt;
+                }
+            }
+
+            if needsend {
+                let segment = self.snd_buf[i].clone();
+                self.push_segment(&segment);
+                if segment.xmit >= self.dead_link {
+                    self.dead = true;
+                }
+            }
+        }
+
+        self.flush_buffer();
+
+        if change {
+            let inflight = self.snd_nxt.wrapping_sub(self.snd_una);
+            self.ssthresh = (inflight / 2).max(KCP_THRESH_MIN);
+            self.cwnd = self.ssthresh + resent;
+            self.incr = self.cwnd * self.mss as u32;
+        }
+        if lost {
+            self.ssthresh = (cwnd / 2).max(KCP_THRESH_MIN);
+            self.cwnd = 1;
+            self.incr = self.mss as u32;
+        }
+        if self.cwnd < 1 {
+            self.cwnd = 1;
+            self.incr = self.mss as u32;
+        }
+    }
+
+    /// Advances the clock to `current` ms and flushes pending segments when
+    /// the flush interval has elapsed.
+    pub fn update(&mut self, current: u32) {
+        self.current = current;
+        if !self.updated {
+            self.updated = true;
+            self.ts_flush = current;
+        }
+
+        let mut slap = timediff(current, self.ts_flush);
+        if !(-10000..10000).contains(&slap) {
+            self.ts_flush = current;
+            slap = 0;
+        }
+        if slap >= 0 {
+            self.ts_flush = self.ts_flush.wrapping_add(self.interval);
+            if timediff(current, self.ts_flush) >= 0 {
+                self.ts_flush = current.wrapping_add(self.interval);
+            }
+            self.flush();
+        }
+    }
+}
+
+/// Tuning of a KCP session, mirroring the kcp-go setters frp calls.
+#[derive(Debug, Clone)]
+pub struct KcpConfig {
+    pub conv: u32,
+    pub mtu: usize,
+    pub snd_wnd: u32,
+    pub rcv_wnd: u32,
+    pub nodelay: u32,
+    pub interval: u32,
+    pub resend: u32,
+    pub nocwnd: bool,
+}
+
+impl KcpConfig {
+    /// Parameters frpc uses when dialing frps's kcp_bind_port. kcp-go picks a
+    /// random conversation id for every dialed session.
+    pub fn transport() -> Self {
+        Self {
+            conv: rand::random(),
+            mtu: 1350,
+            snd_wnd: 128,
+            rcv_wnd: 512,
+            nodelay: 1,
+            interval: 20,
+            resend: 2,
+            nocwnd: true,
+        }
+    }
+
+    /// Parameters of `frpNet.NewKCPConnFromUDP`, used by xtcp.
+    pub fn xtcp() -> Self {
+        Self {
+            conv: 1,
+            mtu: 1350,
+            snd_wnd: 1024,
+            rcv_wnd: 1024,
+            nodelay: 1,
+            interval: 20,
+            resend: 2,
+            nocwnd: true,
+        }
+    }
+}
+
+/// kcp-go's FEC layer. Every `FEC_DATA_SHARDS` data packets are followed by
+/// `FEC_PARITY_SHARDS` Reed-Solomon parity packets, and data packets lost from
+/// a group are rebuilt as soon as enough of its shards arrived. Shards cover a
+/// packet from its size field on, zero padded to the longest in the group.
+struct FecCodec {
+    rs: ReedSolomon,
+    next: u32,
+    paws: u32,
+    // data shards of the group being encoded
+    shards: Vec<Vec<u8>>,
+    // groups being decoded, oldest first
+    groups: VecDeque<FecGroup>,
+}
+
+struct FecGroup {
+    begin: u32,
+    shards: Vec<Option<Vec<u8>>>,
+    data: usize,
+    total: usize,
+    done: bool,
+}
+
+impl FecCodec {
+    fn new() -> Self {
+        let shard_size = FEC_DATA_SHARDS + FEC_PARITY_SHARDS;
+        Self {
+            rs: ReedSolomon::new(FEC_DATA_SHARDS as usize, FEC_PARITY_SHARDS as usize)
+                .expect("valid shard counts"),
+            next: 0,
+            paws: (u32::MAX / shard_size - 1) * shard_size,
+            shards: Vec::with_capacity(FEC_DATA_SHARDS as usize),
+            groups: VecDeque::with_capacity(FEC_RX_GROUPS),
+        }
+    }
+
+    /// Wraps a kcp packet into a data shard, followed by the parity shards of
+    /// its group when it is the group's last one.
+    fn encode(&mut self, kcp_packet: &[u8]) -> Vec<Vec<u8>> {
+        let mut shard = Vec::with_capacity(2 + kcp_packet.len());
+        shard.extend_from_slice(&((kcp_packet.len() + 2) as u16).to_le_bytes());
+        shard.extend_from_slice(kcp_packet);
+
+        let mut packets = vec![self.packet(FEC_TYPE_DATA, &shard)];
+        self.shards.push(shard);
+        if self.shards.len() == FEC_DATA_SHARDS as usize {
+            let size = self.shards.iter().map(Vec::len).max().unwrap_or(0);
+            let mut shards: Vec<Vec<u8>> = self.shards.drain(..).collect();
+            shards.iter_mut().for_each(|shard| shard.resize(size, 0));
+            shards.resize(shards.len() + FEC_PARITY_SHARDS as usize, vec![0; size]);
+            self.rs.encode(&mut shards).expect("equally sized shards");
+            for parity in &shards[FEC_DATA_SHARDS as usize..] {
+                packets.push(self.packet(FEC_TYPE_PARITY, parity));
+            }
+        }
+
+        packets
+    }
+
+    fn packet(&mut self, flag: u16, shard: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(FEC_HEADER_SIZE + shard.len());
+        packet.extend_from_slice(&self.next.to_le_bytes());
+        packet.extend_from_slice(&flag.to_le_bytes());
+        packet.extend_from_slice(shard);
+        self.next = (self.next + 1) % self.paws;
+        packet
+    }
+
+    /// Returns the kcp packets carried by `packet`: its own payload if it is
+    /// a data shard, plus any data shards of its group it allowed to rebuild.
+    fn decode(&mut self, packet: &[u8]) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        if packet.len() < FEC_HEADER_SIZE_PLUS2 {
+            return out;
+        }
+        let seqid = u32::from_le_bytes([packet[0], packet[1], packet[2], packet[3]]);
+        let shard = &packet[FEC_HEADER_SIZE..];
+        match u16::from_le_bytes([packet[4], packet[5]]) {
+            FEC_TYPE_DATA => out.push(Self::payload(shard).unwrap_or(&shard[2..]).to_vec()),
+            FEC_TYPE_PARITY => (),
+            _ => return out,
+        }
+
+        let shard_size = FEC_DATA_SHARDS + FEC_PARITY_SHARDS;
+        let begin = seqid - seqid % shard_size;
+        let index = (seqid % shard_size) as usize;
+        let group = match self.groups.iter().position(|g| g.begin == begin) {
+            Some(i) => &mut self.groups[i],
+            None => {
+                if self.groups.len() == FEC_RX_GROUPS {
+                    self.groups.pop_front();
+                }
+                self.groups.push_back(FecGroup {
+                    begin,
+                    shards: vec![None; shard_size as usize],
+                    data: 0,
+                    total: 0,
+                    done: false,
+                });
+                self.groups.back_mut().expect("just pushed")
+            }
+        };
+        if group.done || group.shards[index].is_some() {
+            return out;
+        }
+        group.shards[index] = Some(shard.to_vec());
+        group.total += 1;
+        if index < FEC_DATA_SHARDS as usize {
+            group.data += 1;
+        }
+
+        if group.data == FEC_DATA_SHARDS as usize {
+            group.done = true;
+        } else if group.total >= FEC_DATA_SHARDS as usize {
+            group.done = true;
+            let size = group
+                .shards
+                .iter()
+                .flatten()
+                .map(Vec::len)
+                .max()
+                .unwrap_or(0);
+            group
+                .shards
+                .iter_mut()
+                .flatten()
+                .for_each(|shard| shard.resize(size, 0));
+            let missing: Vec<usize> = (0..FEC_DATA_SHARDS as usize)
+                .filter(|&i| group.shards[i].is_none())
+                .collect();
+            if self.rs.reconstruct_data(&mut group.shards).is_ok() {
+                for i in missing {
+                    if let Some(data) = group.shards[i].as_deref().and_then(Self::payload) {
+                        out.push(data.to_vec());
+                    }
+                }
+            }
+        }
+
+        out
+    }
+
+    /// The kcp packet in a data shard, cut to the shard's size field.
+    fn payload(shard: &[u8]) -> Option<&[u8]> {
+        let size = u16::from_le_bytes([shard[0], shard[1]]) as usize;
+        (size >= 2 && size <= shard.len()).then(|| &shard[2..size])
+    }
+}
+
+/// A reliable byte stream over a connected udp socket.
+pub struct KcpStream {
+    read_rx: Receiver<Vec<u8>>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    write_tx: PollSender<Vec<u8>>,
+}
+
+impl KcpStream {
+    /// Starts a KCP session on `socket`, which must already be connected to
+    /// the peer.
+    pub fn new(socket: UdpSocket, config: KcpConfig) -> Self {
+        let mut kcp = Kcp::new(config.conv);
+        kcp.set_stream(true);
+        kcp.set_nodelay(
+            config.nodelay,
+            config.interval,
+            config.resend,
+            config.nocwnd,
+        );
+        kcp.set_wndsize(config.snd_wnd, config.rcv_wnd);
+        kcp.set_mtu(config.mtu, FEC_HEADER_SIZE_PLUS2);
+
+        let (read_tx, read_rx) = mpsc::channel(KCP_CHANNEL_SIZE);
+        let (write_tx, write_rx) = mpsc::channel(KCP_CHANNEL_SIZE);
+        tokio::spawn(async move {
+            if let Err(e) = drive(kcp, Arc::new(socket), read_tx, write_rx).await {
+                println!("kcp session closed: {:#}", e);
+            }
+        });
+
+        Self {
+            read_rx,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            write_tx: PollSender::new(write_tx),
+        }
+    }
+}
+
+async fn drive(
+    mut kcp: Kcp,
+    socket: Arc<UdpSocket>,
+    read_tx: Sender<Vec<u8>>,
+    mut write_rx: Receiver<Vec<u8>>,
+) -> Result<()> {
+    let start = Instant::now();
+    let mut fec = FecCodec::new();
+    let mut ticker = interval(Duration::from_millis(kcp.interval() as u64));
+    let mut buf = vec![0; 65536];
+    let mut last_recv = Instant::now();
+    let mut last_keepalive = Instant::now();
+    let mut closed_at: Option<Instant> = None;
+
+    loop {
+        let can_send = (kcp.wait_snd() as u32) < 2 * kcp.snd_wnd();
+        tokio::select! {
+            n = socket.recv(&mut buf) => {
+                let n = match n {
+                    Ok(n) => n,
+                    // icmp unreachable from the peer, it may come back before the idle timeout
+                    Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                // packets that are not ours are dropped like kcp-go does
+                for data in fec.decode(&buf[..n]) {
+                    if data.len() >= KCP_OVERHEAD && kcp.input(&data).is_ok() {
+                        last_recv = Instant::now();
+                    }
+                }
+            }
+            data = write_rx.recv(), if closed_at.is_none() && can_send => {
+                match data {
+                    Some(data) => kcp.send(&data),
+                    None => closed_at = Some(Instant::now()),
+                }
+            }
+            _ = ticker.tick() => {
+                if last_keepalive.elapsed() > KCP_KEEPALIVE_INTERVAL {
+                    kcp.keepalive();
+                    last_keepalive = Instant::now();
+                }
+                kcp.update(start.elapsed().as_millis() as u32);
+                while let Some(packet) = kcp.pop_output() {
+                    for packet in fec.encode(&packet) {
+                        match socket.send(&packet).await {
+                            Ok(_) => (),
+                            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => (),
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                }
+            }
+        }
+
+        while let Ok(permit) = read_tx.try_reserve() {
+            match kcp.recv() {
+                Some(data) => permit.send(data),
+                None => break,
+            }
+        }
+
+        if kcp.is_dead() {
+            return Err(anyhow!("kcp peer stopped acknowledging data"));
+        }
+        if last_recv.elapsed() > KCP_IDLE_TIMEOUT {
+            return Err(anyhow!("kcp peer idle for {:?}", KCP_IDLE_TIMEOUT));
+        }
+        if let Some(closed_at) = closed_at {
+            if kcp.wait_snd() == 0 || closed_at.elapsed() > KCP_LINGER {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl AsyncRead for KcpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.read_pos >= self.read_buf.len() {
+            match self.read_rx.poll_recv(cx) {
+                Poll::Ready(Some(data)) => {
+                    self.read_buf = data;
+                    self.read_pos = 0;
+                }
+                // session gone, report eof
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let n = buf.remaining().min(self.read_buf.len() - self.read_pos);
+        let pos = self.read_pos;
+        buf.put_slice(&self.read_buf[pos..pos + n]);
+        self.read_pos += n;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for KcpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.write_tx.poll_reserve(cx) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(_)) => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            Poll::Pending => return Poll::Pending,
+        }
+
+        let n = buf.len().min(KCP_MAX_WRITE_CHUNK);
+        if self.write_tx.send_item(buf[..n].to_vec()).is_err() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.write_tx.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn bind() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    async fn connect(a: &UdpSocket, b: &UdpSocket) {
+        a.connect(b.local_addr().unwrap()).await.unwrap();
+        b.connect(a.local_addr().unwrap()).await.unwrap();
+    }
+
+    // forwards datagrams from `from` to `to`, dropping every `nth` one
+    fn relay(from: Arc<UdpSocket>, to: Arc<UdpSocket>, nth: usize) {
+        tokio::spawn(async move {
+            let mut buf = vec![0; 65536];
+            let mut count = 0;
+            while let Ok(n) = from.recv(&mut buf).await {
+                count += 1;
+                if count % nth != 0 {
+                    let _ = to.send(&buf[..n]).await;
+                }
+            }
+        });
+    }
+
+    async fn transfer(mut a: KcpStream, mut b: KcpStream) {
+        let data: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+        let expected = data.clone();
+        let writer = tokio::spawn(async move {
+            a.write_all(&data).await.unwrap();
+            a
+        });
+
+        let mut received = vec![0; expected.len()];
+        b.read_exact(&mut received).await.unwrap();
+        assert!(received == expected);
+
+        let mut a = writer.await.unwrap();
+        b.write_all(b"pong").await.unwrap();
+        let mut pong = [0; 4];
+        a.read_exact(&mut pong).await.unwrap();
+        assert_eq!(&pong, b"pong");
+    }
+
+    #[tokio::test]
+    async fn streams_over_loopback() {
+        let (a, b) = (bind().await, bind().await);
+        connect(&a, &b).await;
+
+        let a = KcpStream::new(a, KcpConfig::xtcp());
+        let b = KcpStream::new(b, KcpConfig::xtcp());
+        tokio::time::timeout(Duration::from_secs(10), transfer(a, b))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn streams_over_lossy_link() {
+        let (a, b) = (bind().await, bind().await);
+        let (relay_a, relay_b) = (Arc::new(bind().await), Arc::new(bind().await));
+        connect(&a, &relay_a).await;
+        connect(&b, &relay_b).await;
+        relay(relay_a.clone(), relay_b.clone(), 7);
+        relay(relay_b, relay_a, 7);
+
+        let a = KcpStream::new(a, KcpConfig::xtcp());
+        let b = KcpStream::new(b, KcpConfig::xtcp());
+        tokio::time::timeout(Duration::from_secs(20), transfer(a, b))
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn fec_rebuilds_lost_data_shards() {
+        let packets: Vec<Vec<u8>> = (0..FEC_DATA_SHARDS as usize)
+            .map(|i| vec![i as u8; KCP_OVERHEAD + i * 7])
+            .collect();
+
+        let mut encoder = FecCodec::new();
+        let wire: Vec<Vec<u8>> = packets.iter().flat_map(|p| encoder.encode(p)).collect();
+        assert_eq!(wire.len(), (FEC_DATA_SHARDS + FEC_PARITY_SHARDS) as usize);
+        for (i, packet) in wire.iter().enumerate() {
+            let flag = if i < FEC_DATA_SHARDS as usize {
+                FEC_TYPE_DATA
+            } else {
+                FEC_TYPE_PARITY
+            };
+            assert_eq!(packet[..4], (i as u32).to_le_bytes());
+            assert_eq!(packet[4..6], flag.to_le_bytes());
+        }
+        // the next group starts after the parity seqids
+        assert_eq!(encoder.encode(&packets[0])[0][..4], 13u32.to_le_bytes());
+
+        let mut decoder = FecCodec::new();
+        let mut received = Vec::new();
+        for (i, packet) in wire.iter().enumerate() {
+            if i != 2 && i != 7 {
+                received.extend(decoder.decode(packet));
+            }
+        }
+        assert_eq!(received.len(), packets.len());
+        received.sort();
+        assert_eq!(received, packets);
+
+        // a late copy of a rebuilt shard is only passed through, not rebuilt again
+        assert_eq!(decoder.decode(&wire[2]), vec![packets[2].clone()]);
+        assert!(decoder.decode(&wire[12]).is_empty());
+    }
+}
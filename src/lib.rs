@@ -1,9 +1,21 @@
 pub mod config;
 pub mod control;
 pub mod crypto;
+pub mod dialer;
 pub mod frpc;
+pub mod kcp;
 pub mod msg;
+pub mod plugin;
+pub mod proxy_protocol;
+pub mod quic;
 pub mod service;
+pub mod stream;
+pub mod tls;
+pub mod transport;
+pub mod udp;
+pub mod visitor;
+pub mod websocket;
+pub mod xtcp;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const FRP_VERSION: &str = "0.44.0";
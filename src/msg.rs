@@ -1,14 +1,177 @@
-use anyhow::Result;
+// the Type* constants keep the names of frp's Go message types
+#![allow(non_upper_case_globals)]
+
+use anyhow::{anyhow, Error, Result};
+use bytes::{Buf, BytesMut};
 use chrono::Utc;
+use futures::io::{AsyncRead, AsyncWrite};
 use futures_util::io::{AsyncReadExt, AsyncWriteExt};
 use md5;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, env::consts, mem::size_of};
-use yamux::Stream;
+use std::{collections::HashMap, env::consts};
+use tokio_util::codec::{Decoder, Encoder};
 
 use crate::{config::Config, crypto::FrpCoder};
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Every message of the frp 0.44 control protocol.
+#[derive(Debug)]
+pub enum Message {
+    // boxed, they are several times the size of the other messages
+    Login(Box<Login>),
+    LoginResp(LoginResp),
+    NewProxy(Box<NewProxy>),
+    NewProxyResp(NewProxyResp),
+    CloseProxy(CloseProxy),
+    NewWorkConn(NewWorkConn),
+    ReqWorkConn(ReqWorkConn),
+    StartWorkConn(StartWorkConn),
+    NewVisitorConn(NewVisitorConn),
+    NewVisitorConnResp(NewVisitorConnResp),
+    Ping(Ping),
+    Pong(Pong),
+    UdpPacket(UdpPacket),
+    NatHoleVisitor(NatHoleVisitor),
+    NatHoleClient(NatHoleClient),
+    NatHoleResp(NatHoleResp),
+    NatHoleClientDetectOK(NatHoleClientDetectOK),
+    NatHoleSid(NatHoleSid),
+}
+
+impl Message {
+    pub fn msg_type(&self) -> MsgType {
+        match self {
+            Message::Login(_) => TypeLogin,
+            Message::LoginResp(_) => TypeLoginResp,
+            Message::NewProxy(_) => TypeNewProxy,
+            Message::NewProxyResp(_) => TypeNewProxyResp,
+            Message::CloseProxy(_) => TypeCloseProxy,
+            Message::NewWorkConn(_) => TypeNewWorkConn,
+            Message::ReqWorkConn(_) => TypeReqWorkConn,
+            Message::StartWorkConn(_) => TypeStartWorkConn,
+            Message::NewVisitorConn(_) => TypeNewVisitorConn,
+            Message::NewVisitorConnResp(_) => TypeNewVisitorConnResp,
+            Message::Ping(_) => TypePing,
+            Message::Pong(_) => TypePong,
+            Message::UdpPacket(_) => TypeUDPPacket,
+            Message::NatHoleVisitor(_) => TypeNatHoleVisitor,
+            Message::NatHoleClient(_) => TypeNatHoleClient,
+            Message::NatHoleResp(_) => TypeNatHoleResp,
+            Message::NatHoleClientDetectOK(_) => TypeNatHoleClientDetectOK,
+            Message::NatHoleSid(_) => TypeNatHoleSid,
+        }
+    }
+
+    /// Serializes the message body and prepends the frp message header.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let body = match self {
+            Message::Login(m) => serde_json::to_vec(m)?,
+            Message::LoginResp(m) => serde_json::to_vec(m)?,
+            Message::NewProxy(m) => serde_json::to_vec(m)?,
+            Message::NewProxyResp(m) => serde_json::to_vec(m)?,
+            Message::CloseProxy(m) => serde_json::to_vec(m)?,
+            Message::NewWorkConn(m) => serde_json::to_vec(m)?,
+            Message::ReqWorkConn(m) => serde_json::to_vec(m)?,
+            Message::StartWorkConn(m) => serde_json::to_vec(m)?,
+            Message::NewVisitorConn(m) => serde_json::to_vec(m)?,
+            Message::NewVisitorConnResp(m) => serde_json::to_vec(m)?,
+            Message::Ping(m) => serde_json::to_vec(m)?,
+            Message::Pong(m) => serde_json::to_vec(m)?,
+            Message::UdpPacket(m) => serde_json::to_vec(m)?,
+            Message::NatHoleVisitor(m) => serde_json::to_vec(m)?,
+            Message::NatHoleClient(m) => serde_json::to_vec(m)?,
+            Message::NatHoleResp(m) => serde_json::to_vec(m)?,
+            Message::NatHoleClientDetectOK(m) => serde_json::to_vec(m)?,
+            Message::NatHoleSid(m) => serde_json::to_vec(m)?,
+        };
+
+        let hdr = MsgHeader::new(self.msg_type(), body.len() as u64);
+        let mut data = msg_header_encode(&hdr).to_vec();
+        data.extend_from_slice(&body);
+
+        Ok(data)
+    }
+
+    /// Parses the body of a message whose header has already been read.
+    pub fn decode(msg_type: MsgType, body: &[u8]) -> Result<Self> {
+        let msg = match msg_type {
+            TypeLogin => Message::Login(serde_json::from_slice(body)?),
+            TypeLoginResp => Message::LoginResp(serde_json::from_slice(body)?),
+            TypeNewProxy => Message::NewProxy(serde_json::from_slice(body)?),
+            TypeNewProxyResp => Message::NewProxyResp(serde_json::from_slice(body)?),
+            TypeCloseProxy => Message::CloseProxy(serde_json::from_slice(body)?),
+            TypeNewWorkConn => Message::NewWorkConn(serde_json::from_slice(body)?),
+            TypeReqWorkConn => Message::ReqWorkConn(serde_json::from_slice(body)?),
+            TypeStartWorkConn => Message::StartWorkConn(serde_json::from_slice(body)?),
+            TypeNewVisitorConn => Message::NewVisitorConn(serde_json::from_slice(body)?),
+            TypeNewVisitorConnResp => Message::NewVisitorConnResp(serde_json::from_slice(body)?),
+            TypePing => Message::Ping(serde_json::from_slice(body)?),
+            TypePong => Message::Pong(serde_json::from_slice(body)?),
+            TypeUDPPacket => Message::UdpPacket(serde_json::from_slice(body)?),
+            TypeNatHoleVisitor => Message::NatHoleVisitor(serde_json::from_slice(body)?),
+            TypeNatHoleClient => Message::NatHoleClient(serde_json::from_slice(body)?),
+            TypeNatHoleResp => Message::NatHoleResp(serde_json::from_slice(body)?),
+            TypeNatHoleClientDetectOK => {
+                Message::NatHoleClientDetectOK(serde_json::from_slice(body)?)
+            }
+            TypeNatHoleSid => Message::NatHoleSid(serde_json::from_slice(body)?),
+            _ => return Err(anyhow!("unknown message type {:?}", msg_type)),
+        };
+
+        Ok(msg)
+    }
+}
+
+/// Writes one unencrypted message, as used on work connections.
+pub async fn write_msg<S>(stream: &mut S, msg: &Message) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream.write_all(&msg.encode()?).await?;
+
+    Ok(())
+}
+
+/// Reads one unencrypted message without consuming any byte after it.
+pub async fn read_msg<S>(stream: &mut S) -> Result<Message>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut msg_hdr = [0; MSG_HEADER_SIZE];
+    stream.read_exact(&mut msg_hdr).await?;
+    let header = msg_header_decode(&msg_hdr);
+    if header.len > MAX_MSG_LENGTH as u64 {
+        return Err(anyhow!(
+            "message length {} exceeds limit {}",
+            header.len,
+            MAX_MSG_LENGTH
+        ));
+    }
+    let mut body = vec![0; header.len as usize];
+    stream.read_exact(&mut body).await?;
+
+    Message::decode(header.msg_type, &body)
+}
+
+/// Parses a message carried whole in one udp datagram.
+pub fn decode_msg(buf: &[u8]) -> Result<Message> {
+    if buf.len() < MSG_HEADER_SIZE {
+        return Err(anyhow!("message shorter than its header"));
+    }
+    let header = msg_header_decode(buf[0..MSG_HEADER_SIZE].try_into().unwrap());
+    let body = &buf[MSG_HEADER_SIZE..];
+    if header.len != body.len() as u64 {
+        return Err(anyhow!(
+            "message length {} doesn't match datagram body {}",
+            header.len,
+            body.len()
+        ));
+    }
+
+    Message::decode(header.msg_type, body)
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 pub struct Login {
     version: String,
     hostname: String,
@@ -17,14 +180,18 @@ pub struct Login {
     user: String,
     privilege_key: String,
     timestamp: i64,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    run_id: String,
     metas: HashMap<String, String>,
-    pool_count: i32,
+    pool_count: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 pub struct LoginResp {
     version: String,
     run_id: Option<String>,
+    server_udp_port: u16,
     error: Option<String>,
 }
 
@@ -42,31 +209,27 @@ impl Login {
             user: "rust-frp-client".to_string(),
             privilege_key,
             timestamp,
+            run_id: "".to_string(),
             metas,
-            pool_count: 1,
+            pool_count: cfg.pool_count(),
         }
     }
 
-    pub async fn send_msg(&self, main_stream: &mut Stream) -> Result<LoginResp> {
-        let frame = self.to_string().into_bytes();
-        let hdr = MsgHeader::new(TypeLogin, frame.len() as u64);
-        main_stream
-            .write_all(&msg_header_encode(&hdr).to_vec())
-            .await?;
-        main_stream.write_all(&frame).await?;
-
-        let mut msg_hdr = [0; MSG_HEADER_SIZE];
-        main_stream.read_exact(&mut msg_hdr).await?;
-        let header: MsgHeader = msg_header_decode(&msg_hdr.try_into().unwrap());
-        let mut msg = vec![0; header.len as usize];
-        main_stream.read_exact(&mut msg).await?;
-        let resp = String::from_utf8_lossy(&msg);
-
-        Ok(serde_json::from_str(&resp)?)
+    // reuse the run_id of the previous session so frps replaces the old control
+    pub fn set_run_id(&mut self, run_id: &str) {
+        self.run_id = run_id.to_string()
     }
 
-    fn to_string(&self) -> String {
-        serde_json::to_string(&self).unwrap()
+    pub async fn send_msg<S>(self, main_stream: &mut S) -> Result<LoginResp>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        write_msg(main_stream, &Message::Login(Box::new(self))).await?;
+
+        match read_msg(main_stream).await? {
+            Message::LoginResp(resp) => Ok(resp),
+            msg => Err(anyhow!("expect login response, got {:?}", msg.msg_type())),
+        }
     }
 }
 
@@ -78,6 +241,10 @@ impl LoginResp {
         }
     }
 
+    pub fn server_udp_port(&self) -> u16 {
+        self.server_udp_port
+    }
+
     pub fn error(&self) -> &str {
         match self.error {
             None => "",
@@ -93,26 +260,11 @@ fn get_privilege_key(timestamp: i64, auth_token: &str) -> String {
     format!("{:x}", digest)
 }
 
-pub struct ReqWorkConn;
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ReqWorkConn {}
 
-impl ReqWorkConn {
-    pub async fn handle_req_work_conn(
-        main_stream: &mut Stream,
-        decoder: &mut FrpCoder,
-    ) -> Result<()> {
-        let mut buf = [0; 128];
-        let n = main_stream.read(&mut buf).await?;
-        assert_eq!((n < 128), true);
-        println!("ReqWorkConn read {}", n);
-        let mut dbuf = buf[0..n].to_vec();
-        decoder.decrypt(&mut dbuf);
-        println!("dbuf {:?}", dbuf);
-
-        Ok(())
-    }
-}
-
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 pub struct NewWorkConn {
     run_id: String,
     privilege_key: String,
@@ -130,31 +282,80 @@ impl NewWorkConn {
             timestamp,
         }
     }
-
-    pub fn to_string(&self) -> String {
-        serde_json::to_string(&self).unwrap()
-    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 pub struct StartWorkConn {
     pub proxy_name: String,
     src_addr: String,
     dst_addr: String,
     src_port: u16,
     dst_port: u16,
+    error: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl StartWorkConn {
+    pub fn error(&self) -> &str {
+        &self.error
+    }
+
+    /// The user's address as seen by frps.
+    pub fn src_addr(&self) -> &str {
+        &self.src_addr
+    }
+
+    pub fn src_port(&self) -> u16 {
+        self.src_port
+    }
+
+    /// The frps address the user connected to.
+    pub fn dst_addr(&self) -> &str {
+        &self.dst_addr
+    }
+
+    pub fn dst_port(&self) -> u16 {
+        self.dst_port
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 pub struct NewProxy {
     proxy_name: String,
     proxy_type: String,
+    use_encryption: bool,
+    use_compression: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metas: Option<HashMap<String, String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     remote_port: Option<u16>,
     #[serde(skip_serializing_if = "Option::is_none")]
     custom_domains: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     subdomain: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    locations: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    http_user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    http_pwd: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host_header_rewrite: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    headers: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    route_by_http_user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sk: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allow_users: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    multiplexer: Option<String>,
 }
 
 impl NewProxy {
@@ -162,9 +363,7 @@ impl NewProxy {
         Self {
             proxy_name: proxy_name.to_string(),
             proxy_type: proxy_type.to_string(),
-            remote_port: None,
-            custom_domains: None,
-            subdomain: None,
+            ..Default::default()
         }
     }
 
@@ -180,32 +379,186 @@ impl NewProxy {
         self.subdomain = Some(subdomain.to_string())
     }
 
-    pub async fn send_msg(&self, main_stream: &mut Stream, encoder: &mut FrpCoder) -> Result<()> {
-        let frame = self.to_string().into_bytes();
-        let cap = frame.len() + MSG_HEADER_SIZE;
-        let mut data: Vec<u8> = vec![0; cap];
-        data[0] = TypeNewProxy.0;
-        data[1..MSG_HEADER_SIZE].copy_from_slice(&frame.len().to_be_bytes());
-        data[MSG_HEADER_SIZE..].copy_from_slice(&frame);
+    pub fn set_sk(&mut self, sk: &str) {
+        self.sk = Some(sk.to_string())
+    }
 
-        encoder.encypt(&mut data);
-        main_stream.write_all(&data).await?;
+    pub fn set_allow_users(&mut self, allow_users: &Vec<String>) {
+        self.allow_users = Some(allow_users.clone())
+    }
 
-        Ok(())
+    pub fn set_use_encryption(&mut self, use_encryption: bool) {
+        self.use_encryption = use_encryption
     }
 
-    fn to_string(&self) -> String {
-        serde_json::to_string(&self).unwrap()
+    pub fn set_use_compression(&mut self, use_compression: bool) {
+        self.use_compression = use_compression
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 pub struct NewProxyResp {
+    pub proxy_name: String,
+    pub remote_addr: String,
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct CloseProxy {
+    pub proxy_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct NewVisitorConn {
     proxy_name: String,
-    remote_addr: String,
+    sign_key: String,
+    timestamp: i64,
+    use_encryption: bool,
+    use_compression: bool,
+}
+
+impl NewVisitorConn {
+    pub fn new(proxy_name: &str, sk: &str) -> Self {
+        let timestamp = Utc::now().timestamp();
+        let sign_key = get_privilege_key(timestamp, sk);
+
+        Self {
+            proxy_name: proxy_name.to_string(),
+            sign_key,
+            timestamp,
+            use_encryption: false,
+            use_compression: false,
+        }
+    }
+
+    pub fn set_use_encryption(&mut self, use_encryption: bool) {
+        self.use_encryption = use_encryption
+    }
+
+    pub fn set_use_compression(&mut self, use_compression: bool) {
+        self.use_compression = use_compression
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct NewVisitorConnResp {
+    pub proxy_name: String,
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Ping {
+    privilege_key: String,
+    timestamp: i64,
+}
+
+impl Ping {
+    pub fn new(cfg: &Config) -> Self {
+        let timestamp = Utc::now().timestamp();
+        let privilege_key = get_privilege_key(timestamp, cfg.auth_token());
+
+        Self {
+            privilege_key,
+            timestamp,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Pong {
     error: String,
 }
 
+impl Pong {
+    pub fn error(&self) -> &str {
+        &self.error
+    }
+}
+
+/// Go's `net.UDPAddr` as encoded by encoding/json.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct UdpAddr {
+    #[serde(rename = "IP")]
+    pub ip: String,
+    #[serde(rename = "Port")]
+    pub port: u16,
+    #[serde(rename = "Zone")]
+    pub zone: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct UdpPacket {
+    #[serde(rename = "c")]
+    pub content: String,
+    #[serde(rename = "l", skip_serializing_if = "Option::is_none")]
+    pub local_addr: Option<UdpAddr>,
+    #[serde(rename = "r", skip_serializing_if = "Option::is_none")]
+    pub remote_addr: Option<UdpAddr>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct NatHoleVisitor {
+    proxy_name: String,
+    sign_key: String,
+    timestamp: i64,
+}
+
+impl NatHoleVisitor {
+    pub fn new(proxy_name: &str, sk: &str) -> Self {
+        let timestamp = Utc::now().timestamp();
+        let sign_key = get_privilege_key(timestamp, sk);
+
+        Self {
+            proxy_name: proxy_name.to_string(),
+            sign_key,
+            timestamp,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct NatHoleClient {
+    proxy_name: String,
+    sid: String,
+}
+
+impl NatHoleClient {
+    pub fn new(proxy_name: &str, sid: &str) -> Self {
+        Self {
+            proxy_name: proxy_name.to_string(),
+            sid: sid.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct NatHoleResp {
+    pub sid: String,
+    pub visitor_addr: String,
+    pub client_addr: String,
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct NatHoleClientDetectOK {}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct NatHoleSid {
+    pub sid: String,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct MsgHeader {
     pub msg_type: MsgType,
@@ -221,7 +574,6 @@ impl MsgHeader {
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct MsgType(u8);
 
-#[allow(non_camel_case_types)]
 pub const TypeLogin: MsgType = MsgType('o' as u8);
 pub const TypeLoginResp: MsgType = MsgType('1' as u8);
 
@@ -248,6 +600,8 @@ pub const TypeNatHoleClientDetectOK: MsgType = MsgType('d' as u8);
 pub const TypeNatHoleSid: MsgType = MsgType('5' as u8);
 
 pub const MSG_HEADER_SIZE: usize = 9;
+// same limit as frp's defaultMaxMsgLength
+pub const MAX_MSG_LENGTH: usize = 10240;
 
 pub fn msg_header_encode(hdr: &MsgHeader) -> [u8; MSG_HEADER_SIZE] {
     let mut buf = [0; MSG_HEADER_SIZE];
@@ -265,3 +619,178 @@ pub fn msg_header_decode(buf: &[u8; MSG_HEADER_SIZE]) -> MsgHeader {
         ]),
     }
 }
+
+/// Frames the encrypted control stream.
+///
+/// Incoming bytes are decrypted as a stream through `FrpCoder` as soon as they
+/// arrive, so a message may be split over several reads or several messages
+/// may share one read. Outgoing frames are encrypted with the same key and the iv
+/// frpc sent to frps.
+pub struct MsgCodec {
+    dec: FrpCoder,
+    enc: FrpCoder,
+    plain: BytesMut,
+    max_frame_len: usize,
+}
+
+impl MsgCodec {
+    pub fn new(dec: FrpCoder, enc: FrpCoder) -> Self {
+        Self {
+            dec,
+            enc,
+            plain: BytesMut::new(),
+            max_frame_len: MAX_MSG_LENGTH,
+        }
+    }
+
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize) {
+        self.max_frame_len = max_frame_len
+    }
+}
+
+impl Decoder for MsgCodec {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if !src.is_empty() {
+            let mut data = src.split().to_vec();
+            self.dec.decrypt(&mut data)?;
+            self.plain.extend_from_slice(&data);
+        }
+
+        if self.plain.len() < MSG_HEADER_SIZE {
+            return Ok(None);
+        }
+        let header = msg_header_decode(self.plain[0..MSG_HEADER_SIZE].try_into().unwrap());
+        let len = header.len as usize;
+        if header.len > self.max_frame_len as u64 {
+            return Err(anyhow!(
+                "message length {} exceeds limit {}",
+                header.len,
+                self.max_frame_len
+            ));
+        }
+        if self.plain.len() < MSG_HEADER_SIZE + len {
+            self.plain.reserve(MSG_HEADER_SIZE + len - self.plain.len());
+            return Ok(None);
+        }
+
+        self.plain.advance(MSG_HEADER_SIZE);
+        let body = self.plain.split_to(len);
+
+        Ok(Some(Message::decode(header.msg_type, &body)?))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if self.plain.is_empty() => Ok(None),
+            None => Err(anyhow!("stream closed in the middle of a message")),
+        }
+    }
+}
+
+impl Encoder<Message> for MsgCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<()> {
+        let mut data = item.encode()?;
+        self.enc.encypt(&mut data)?;
+        dst.extend_from_slice(&data);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codec() -> MsgCodec {
+        let coder = FrpCoder::new("token", [9; 16]);
+        MsgCodec::new(coder.clone(), coder)
+    }
+
+    fn sid(sid: &str) -> Message {
+        Message::NatHoleSid(NatHoleSid {
+            sid: sid.to_string(),
+        })
+    }
+
+    fn assert_sid(msg: Option<Message>, expected: &str) {
+        match msg {
+            Some(Message::NatHoleSid(msg)) => assert_eq!(msg.sid, expected),
+            msg => panic!(
+                "expect sid {}, got {:?}",
+                expected,
+                msg.map(|m| m.msg_type())
+            ),
+        }
+    }
+
+    // the encrypted bytes frpc would put on the wire for `msgs`
+    fn wire(msgs: Vec<Message>) -> BytesMut {
+        let mut codec = codec();
+        let mut buf = BytesMut::new();
+        for msg in msgs {
+            codec.encode(msg, &mut buf).unwrap();
+        }
+        buf
+    }
+
+    #[test]
+    fn decode_split_frames() {
+        let wire = wire(vec![sid("first"), sid("second")]);
+        let mut codec = codec();
+        let mut decoded = Vec::new();
+        for byte in wire.iter() {
+            let mut src = BytesMut::from(&[*byte][..]);
+            if let Some(msg) = codec.decode(&mut src).unwrap() {
+                decoded.push(msg);
+            }
+            assert!(src.is_empty());
+        }
+        assert_eq!(decoded.len(), 2);
+        assert_sid(decoded.pop(), "second");
+        assert_sid(decoded.pop(), "first");
+    }
+
+    #[test]
+    fn decode_coalesced_frames() {
+        let mut src = wire(vec![sid("first"), sid("second"), sid("third")]);
+        let mut codec = codec();
+        assert_sid(codec.decode(&mut src).unwrap(), "first");
+        assert_sid(codec.decode(&mut src).unwrap(), "second");
+        assert_sid(codec.decode(&mut src).unwrap(), "third");
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert!(codec.decode_eof(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn decode_rejects_oversized_frames() {
+        let mut header =
+            msg_header_encode(&MsgHeader::new(TypeNatHoleSid, MAX_MSG_LENGTH as u64 + 1)).to_vec();
+        FrpCoder::new("token", [9; 16]).encypt(&mut header).unwrap();
+        let e = codec()
+            .decode(&mut BytesMut::from(&header[..]))
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("exceeds limit"), "{}", e);
+
+        // the limit itself is still fine
+        let mut src = wire(vec![sid(&"x".repeat(MAX_MSG_LENGTH - 10))]);
+        assert_eq!(src.len(), MSG_HEADER_SIZE + MAX_MSG_LENGTH);
+        assert!(codec().decode(&mut src).unwrap().is_some());
+    }
+
+    #[test]
+    fn decode_eof_in_the_middle_of_a_frame() {
+        let wire = wire(vec![sid("first"), sid("second")]);
+        let mut codec = codec();
+        let mut src = BytesMut::from(&wire[..wire.len() - 3]);
+        assert_sid(codec.decode_eof(&mut src).unwrap(), "first");
+        let e = codec.decode_eof(&mut src).err().unwrap();
+        assert!(e.to_string().contains("in the middle"), "{}", e);
+    }
+}
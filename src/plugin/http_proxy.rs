@@ -0,0 +1,243 @@
+use anyhow::Result;
+use futures::{future::BoxFuture, FutureExt};
+use hyper::{
+    client::HttpConnector,
+    header::{self, HeaderValue},
+    server::conn::Http,
+    service::service_fn,
+    Body, Client, Method, Request, Response, StatusCode,
+};
+use std::{collections::HashMap, convert::Infallible, sync::Arc};
+use tokio::{io, net::TcpStream};
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+use super::{http_basic_auth, http_status, remove_hop_by_hop_headers, Plugin};
+use crate::{msg::StartWorkConn, transport::BoxedStream};
+
+/// An HTTP forward proxy on the work stream: absolute-form requests are sent
+/// on to their origin and CONNECT opens a tunnel. Basic auth is required when
+/// plugin_http_user or plugin_http_passwd is set.
+#[derive(Debug)]
+pub struct HttpProxyPlugin {
+    // expected Proxy-Authorization value
+    auth: Option<String>,
+    client: Client<HttpConnector>,
+}
+
+pub fn build(params: &HashMap<String, String>) -> Result<Arc<dyn Plugin>> {
+    Ok(Arc::new(HttpProxyPlugin {
+        auth: http_basic_auth(params),
+        client: Client::new(),
+    }))
+}
+
+impl Plugin for HttpProxyPlugin {
+    fn name(&self) -> &str {
+        "http_proxy"
+    }
+
+    fn handle(&self, conn: BoxedStream, _meta: StartWorkConn) -> BoxFuture<'_, Result<()>> {
+        let auth = self.auth.clone();
+        let client = self.client.clone();
+        let service = service_fn(move |req| serve(req, auth.clone(), client.clone()));
+
+        async move {
+            Http::new()
+                .http1_only(true)
+                .serve_connection(conn.compat(), service)
+                .with_upgrades()
+                .await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+async fn serve(
+    mut req: Request<Body>,
+    auth: Option<String>,
+    client: Client<HttpConnector>,
+) -> Result<Response<Body>, Infallible> {
+    if let Some(auth) = auth {
+        let given = req.headers().get(header::PROXY_AUTHORIZATION);
+        if given.map(HeaderValue::as_bytes) != Some(auth.as_bytes()) {
+            let mut resp = http_status(StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+            resp.headers_mut().insert(
+                header::PROXY_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"frp\""),
+            );
+            return Ok(resp);
+        }
+    }
+
+    if req.method() == Method::CONNECT {
+        let addr = match req.uri().authority() {
+            Some(authority) => authority.to_string(),
+            None => return Ok(http_status(StatusCode::BAD_REQUEST)),
+        };
+        let mut target = match TcpStream::connect(addr.as_str()).await {
+            Ok(target) => target,
+            Err(e) => {
+                println!("http_proxy connect to {} error: {}", addr, e);
+                return Ok(http_status(StatusCode::BAD_GATEWAY));
+            }
+        };
+        // the tunnel starts once hyper has sent the 200 and given up the connection
+        tokio::spawn(async move {
+            let res = async {
+                let mut upgraded = hyper::upgrade::on(&mut req).await?;
+                io::copy_bidirectional(&mut upgraded, &mut target).await?;
+                Ok::<_, anyhow::Error>(())
+            };
+            if let Err(e) = res.await {
+                println!("http_proxy tunnel to {} error: {:#}", addr, e);
+            }
+        });
+
+        return Ok(Response::new(Body::empty()));
+    }
+
+    if req.uri().authority().is_none() {
+        return Ok(http_status(StatusCode::BAD_REQUEST));
+    }
+    remove_hop_by_hop_headers(req.headers_mut());
+    match client.request(req).await {
+        Ok(resp) => Ok(resp),
+        Err(e) => {
+            println!("http_proxy forward error: {}", e);
+            Ok(http_status(StatusCode::BAD_GATEWAY))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use tokio::{
+        io::{duplex, AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream},
+        net::TcpListener,
+        sync::oneshot,
+    };
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    fn serve(params: &[(&str, &str)]) -> DuplexStream {
+        let params = params
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let plugin = build(&params).unwrap();
+        let meta = serde_json::from_value(serde_json::json!({ "proxy_name": "proxy" })).unwrap();
+        let (client_end, plugin_end) = duplex(65536);
+        tokio::spawn(async move { plugin.handle(Box::new(plugin_end.compat()), meta).await });
+
+        client_end
+    }
+
+    async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> String {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    // answers one request with "ok" and hands back the request head it got
+    async fn origin() -> (SocketAddr, oneshot::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let head = read_head(&mut stream).await;
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+                .await
+                .unwrap();
+            tx.send(head).unwrap();
+        });
+
+        (addr, rx)
+    }
+
+    async fn get(conn: &mut DuplexStream, addr: SocketAddr, headers: &str) -> String {
+        let req = format!(
+            "GET http://{}/index.html?a=1 HTTP/1.1\r\nhost: {}\r\n{}\r\n",
+            addr, addr, headers
+        );
+        conn.write_all(req.as_bytes()).await.unwrap();
+        read_head(conn).await
+    }
+
+    #[tokio::test]
+    async fn forwards_absolute_form_requests() {
+        let (addr, head) = origin().await;
+        let mut conn = serve(&[]);
+
+        let resp = get(
+            &mut conn,
+            addr,
+            "connection: keep-alive, x-hop\r\nx-hop: 1\r\nx-end: 1\r\nte: trailers\r\n\
+             proxy-connection: keep-alive\r\nupgrade: h2c\r\n",
+        )
+        .await;
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+        let mut body = [0; 2];
+        conn.read_exact(&mut body).await.unwrap();
+        assert_eq!(&body, b"ok");
+
+        let head = head.await.unwrap().to_ascii_lowercase();
+        assert!(
+            head.starts_with("get /index.html?a=1 http/1.1\r\n"),
+            "{}",
+            head
+        );
+        assert!(head.contains("x-end: 1\r\n"), "{}", head);
+        for hop in ["x-hop", "te:", "proxy-connection", "upgrade"] {
+            assert!(!head.contains(hop), "{} in {}", hop, head);
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_tunnels_bytes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut read, mut write) = stream.split();
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+        });
+        let mut conn = serve(&[]);
+
+        let req = format!("CONNECT {} HTTP/1.1\r\nhost: {}\r\n\r\n", addr, addr);
+        conn.write_all(req.as_bytes()).await.unwrap();
+        let resp = read_head(&mut conn).await;
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+
+        conn.write_all(b"\x00hello tunnel").await.unwrap();
+        let mut echo = [0; 13];
+        conn.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"\x00hello tunnel");
+    }
+
+    #[tokio::test]
+    async fn proxy_auth_required() {
+        let (addr, head) = origin().await;
+        let mut conn = serve(&[("plugin_http_user", "abc")]);
+
+        let resp = get(&mut conn, addr, "").await;
+        assert!(resp.starts_with("HTTP/1.1 407 "), "{}", resp);
+        assert!(
+            resp.contains("proxy-authenticate: Basic realm=\"frp\"\r\n"),
+            "{}",
+            resp
+        );
+
+        // "abc:" with the empty password
+        let resp = get(&mut conn, addr, "proxy-authorization: Basic YWJjOg==\r\n").await;
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+        let head = head.await.unwrap().to_ascii_lowercase();
+        assert!(!head.contains("proxy-authorization"), "{}", head);
+    }
+}
@@ -0,0 +1,264 @@
+use anyhow::{anyhow, Context, Result};
+use futures::{future::BoxFuture, FutureExt};
+use hyper::{
+    client::HttpConnector,
+    header::{self, HeaderValue},
+    server::conn::Http,
+    service::service_fn,
+    Body, Client, Request, Response, StatusCode, Uri,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use rustls::ServerConfig;
+use std::{collections::HashMap, convert::Infallible, sync::Arc};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+
+use super::{http_status, remove_hop_by_hop_headers, Plugin};
+use crate::{msg::StartWorkConn, tls, transport::BoxedStream};
+
+/// Terminates the user's tls with plugin_crt_path and plugin_key_path and
+/// reverse proxies the requests to plugin_local_addr, over plain HTTP for
+/// https2http and a new tls connection for https2https.
+#[derive(Debug)]
+pub struct Https2HttpPlugin {
+    name: &'static str,
+    tls_config: Arc<ServerConfig>,
+    backend: Arc<Backend>,
+}
+
+#[derive(Debug)]
+struct Backend {
+    scheme: &'static str,
+    local_addr: String,
+    host_header_rewrite: Option<String>,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+pub fn build_http(params: &HashMap<String, String>) -> Result<Arc<dyn Plugin>> {
+    build("https2http", "http", params)
+}
+
+pub fn build_https(params: &HashMap<String, String>) -> Result<Arc<dyn Plugin>> {
+    build("https2https", "https", params)
+}
+
+fn build(
+    name: &'static str,
+    scheme: &'static str,
+    params: &HashMap<String, String>,
+) -> Result<Arc<dyn Plugin>> {
+    let param = |key: &str| params.get(key).filter(|v| !v.is_empty());
+    let missing = |key: &str| anyhow!("{} plugin needs {}", name, key);
+    let crt_path = param("plugin_crt_path").ok_or_else(|| missing("plugin_crt_path"))?;
+    let key_path = param("plugin_key_path").ok_or_else(|| missing("plugin_key_path"))?;
+    let local_addr = param("plugin_local_addr").ok_or_else(|| missing("plugin_local_addr"))?;
+
+    let mut server_config = tls::server_config(crt_path, key_path)?;
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    // like frp, the local backend's certificate is not verified
+    let connector = HttpsConnectorBuilder::new()
+        .with_tls_config(tls::client_config("", "", "")?)
+        .https_or_http()
+        .enable_http1()
+        .build();
+
+    Ok(Arc::new(Https2HttpPlugin {
+        name,
+        tls_config: Arc::new(server_config),
+        backend: Arc::new(Backend {
+            scheme,
+            local_addr: local_addr.clone(),
+            host_header_rewrite: param("plugin_host_header_rewrite").cloned(),
+            client: Client::builder().build(connector),
+        }),
+    }))
+}
+
+impl Plugin for Https2HttpPlugin {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn handle(&self, conn: BoxedStream, meta: StartWorkConn) -> BoxFuture<'_, Result<()>> {
+        let backend = self.backend.clone();
+        let user_addr = meta.src_addr().to_string();
+        let service = service_fn(move |req| {
+            let backend = backend.clone();
+            let user_addr = user_addr.clone();
+            async move { Ok::<_, Infallible>(backend.forward(req, &user_addr).await) }
+        });
+
+        async move {
+            let tls_stream = TlsAcceptor::from(self.tls_config.clone())
+                .accept(conn.compat())
+                .await
+                .context("tls handshake with user")?;
+            Http::new()
+                .http1_only(true)
+                .serve_connection(tls_stream, service)
+                .await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+impl Backend {
+    async fn forward(&self, mut req: Request<Body>, user_addr: &str) -> Response<Body> {
+        let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+        *req.uri_mut() =
+            match format!("{}://{}{}", self.scheme, self.local_addr, path).parse::<Uri>() {
+                Ok(uri) => uri,
+                Err(_) => return http_status(StatusCode::BAD_REQUEST),
+            };
+
+        let headers = req.headers_mut();
+        remove_hop_by_hop_headers(headers);
+        if let Some(host) = headers.get(header::HOST).cloned() {
+            headers.insert("x-forwarded-host", host);
+        }
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+        if !user_addr.is_empty() {
+            // append to the chain of any proxy in front of frps
+            let forwarded_for = match headers.get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
+                Some(chain) => format!("{}, {}", chain, user_addr),
+                None => user_addr.to_string(),
+            };
+            if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+                headers.insert("x-forwarded-for", value);
+            }
+        }
+        if let Some(host) = &self.host_header_rewrite {
+            match HeaderValue::from_str(host) {
+                Ok(host) => headers.insert(header::HOST, host),
+                Err(_) => return http_status(StatusCode::BAD_REQUEST),
+            };
+        }
+
+        match self.client.request(req).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                println!(
+                    "{} forward to {} error: {}",
+                    self.scheme, self.local_addr, e
+                );
+                http_status(StatusCode::BAD_GATEWAY)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::TestPki;
+    use rustls::ServerName;
+    use std::{convert::TryFrom, net::SocketAddr};
+    use tokio::{
+        io::{duplex, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        net::TcpListener,
+        sync::oneshot,
+    };
+    use tokio_rustls::TlsConnector;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> String {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(head).unwrap().to_ascii_lowercase()
+    }
+
+    async fn answer<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, tx: oneshot::Sender<String>) {
+        let head = read_head(&mut stream).await;
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+            .await
+            .unwrap();
+        stream.flush().await.unwrap();
+        tx.send(head).unwrap();
+    }
+
+    // a local backend, over tls for https2https, handing back the request
+    // head it got
+    async fn backend(pki: &TestPki, tls: bool) -> (SocketAddr, oneshot::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(
+            tls::server_config(&pki.path("localhost.crt"), &pki.path("localhost.key")).unwrap(),
+        ));
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            if tls {
+                answer(acceptor.accept(stream).await.unwrap(), tx).await;
+            } else {
+                answer(stream, tx).await;
+            }
+        });
+
+        (addr, rx)
+    }
+
+    async fn request(name: &str, pki: &TestPki, local_addr: SocketAddr) {
+        let params = [
+            ("plugin_crt_path", pki.path("localhost.crt")),
+            ("plugin_key_path", pki.path("localhost.key")),
+            ("plugin_local_addr", local_addr.to_string()),
+            ("plugin_host_header_rewrite", "backend.local".to_string()),
+        ];
+        let params = params
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+        let plugin = crate::plugin::create(name, &params).unwrap();
+        let meta = serde_json::from_value(serde_json::json!({
+            "proxy_name": "web",
+            "src_addr": "1.2.3.4",
+            "src_port": 5678,
+        }))
+        .unwrap();
+        let (user_end, plugin_end) = duplex(65536);
+        tokio::spawn(async move { plugin.handle(Box::new(plugin_end.compat()), meta).await });
+
+        // the user trusts the CA that signed plugin_crt_path
+        let connector = TlsConnector::from(Arc::new(
+            tls::client_config("", "", &pki.path("ca.crt")).unwrap(),
+        ));
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut user = connector.connect(server_name, user_end).await.unwrap();
+        user.write_all(
+            b"GET /app?q=1 HTTP/1.1\r\nhost: example.com\r\nx-forwarded-for: 10.0.0.1\r\n\r\n",
+        )
+        .await
+        .unwrap();
+        user.flush().await.unwrap();
+        let resp = read_head(&mut user).await;
+        assert!(resp.starts_with("http/1.1 200 ok\r\n"), "{}", resp);
+        let mut body = [0; 2];
+        user.read_exact(&mut body).await.unwrap();
+        assert_eq!(&body, b"ok");
+    }
+
+    #[tokio::test]
+    async fn forwards_with_forwarded_headers() {
+        let pki = TestPki::new();
+        for (name, tls) in [("https2http", false), ("https2https", true)] {
+            let (addr, head) = backend(&pki, tls).await;
+            request(name, &pki, addr).await;
+
+            let head = head.await.unwrap();
+            assert!(head.starts_with("get /app?q=1 http/1.1\r\n"), "{}", head);
+            for header in [
+                "host: backend.local\r\n",
+                "x-forwarded-host: example.com\r\n",
+                "x-forwarded-proto: https\r\n",
+                "x-forwarded-for: 10.0.0.1, 1.2.3.4\r\n",
+            ] {
+                assert!(head.contains(header), "{} {}", name, head);
+            }
+        }
+    }
+}
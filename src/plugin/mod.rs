@@ -0,0 +1,116 @@
+use anyhow::{anyhow, Context, Result};
+use futures::future::BoxFuture;
+use hyper::{
+    header::{self, HeaderMap},
+    Body, Response, StatusCode,
+};
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use crate::{config::Config, msg::StartWorkConn, transport::BoxedStream};
+
+pub mod http_proxy;
+pub mod https2http;
+pub mod socks5;
+pub mod static_file;
+#[cfg(unix)]
+pub mod unix_domain_socket;
+
+/// Serves work connections in process instead of forwarding them to
+/// local_ip:local_port. `conn` has encryption and compression already undone.
+pub trait Plugin: fmt::Debug + Send + Sync {
+    fn name(&self) -> &str;
+
+    fn handle(&self, conn: BoxedStream, meta: StartWorkConn) -> BoxFuture<'_, Result<()>>;
+}
+
+// only meaningful for a single hop, never forwarded
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Creates a plugin from the proxy's `plugin_*` keys.
+pub type PluginBuilder = fn(&HashMap<String, String>) -> Result<Arc<dyn Plugin>>;
+
+// the plugins a proxy can name in its plugin key
+const REGISTRY: &[(&str, PluginBuilder)] = &[
+    ("http_proxy", http_proxy::build),
+    ("https2http", https2http::build_http),
+    ("https2https", https2http::build_https),
+    ("socks5", socks5::build),
+    ("static_file", static_file::build),
+    #[cfg(unix)]
+    ("unix_domain_socket", unix_domain_socket::build),
+];
+
+/// Creates the plugin registered under `name`.
+pub fn create(name: &str, params: &HashMap<String, String>) -> Result<Arc<dyn Plugin>> {
+    let (_, builder) = REGISTRY
+        .iter()
+        .find(|(plugin, _)| *plugin == name)
+        .ok_or_else(|| anyhow!("unknown plugin {}", name))?;
+
+    builder(params)
+}
+
+/// Creates the plugins of all proxies that set one, keyed by proxy name.
+pub fn create_all(cfg: &Config) -> Result<HashMap<String, Arc<dyn Plugin>>> {
+    let mut plugins = HashMap::new();
+    for name in cfg.proxy_names() {
+        let proxy = cfg.get_proxy(&name)?;
+        if let Some(plugin) = &proxy.plugin {
+            let plugin = create(plugin, &proxy.plugin_params)
+                .with_context(|| format!("plugin of proxy [{}]", name))?;
+            plugins.insert(name, plugin);
+        }
+    }
+
+    Ok(plugins)
+}
+
+/// The Basic credentials clients must send when plugin_http_user or
+/// plugin_http_passwd is set, None when auth is off.
+fn http_basic_auth(params: &HashMap<String, String>) -> Option<String> {
+    let user = params.get("plugin_http_user").map_or("", String::as_str);
+    let passwd = params.get("plugin_http_passwd").map_or("", String::as_str);
+    if user.is_empty() && passwd.is_empty() {
+        return None;
+    }
+
+    Some(format!(
+        "Basic {}",
+        base64::encode(format!("{}:{}", user, passwd))
+    ))
+}
+
+/// An empty response with just the status code.
+fn http_status(code: StatusCode) -> Response<Body> {
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = code;
+
+    resp
+}
+
+/// Drops the hop-by-hop headers, including any the Connection header names.
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let named: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    for name in named {
+        headers.remove(name.as_str());
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(*name);
+    }
+}
@@ -0,0 +1,356 @@
+use anyhow::{anyhow, Context, Result};
+use futures::{
+    future::BoxFuture,
+    io::{AsyncReadExt, AsyncWriteExt},
+    FutureExt,
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio::net::TcpStream;
+
+use super::Plugin;
+use crate::{
+    control::proxy,
+    dialer::{
+        SOCKS5_ATYP_DOMAIN, SOCKS5_ATYP_IPV4, SOCKS5_ATYP_IPV6, SOCKS5_AUTH_NONE,
+        SOCKS5_AUTH_PASSWORD, SOCKS5_AUTH_UNACCEPTABLE, SOCKS5_CMD_CONNECT, SOCKS5_VERSION,
+    },
+    msg::StartWorkConn,
+    transport::BoxedStream,
+};
+
+// version of the username/password subnegotiation, RFC 1929
+const SOCKS5_PASSWORD_VERSION: u8 = 0x01;
+
+const SOCKS5_REP_SUCCEEDED: u8 = 0x00;
+const SOCKS5_REP_GENERAL_FAILURE: u8 = 0x01;
+const SOCKS5_REP_HOST_UNREACHABLE: u8 = 0x04;
+const SOCKS5_REP_CONNECTION_REFUSED: u8 = 0x05;
+const SOCKS5_REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const SOCKS5_REP_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// A SOCKS5 server on the work stream, CONNECT only. Username/password auth
+/// is required when plugin_user or plugin_passwd is set.
+#[derive(Debug)]
+pub struct Socks5Plugin {
+    auth: Option<(String, String)>,
+}
+
+pub fn build(params: &HashMap<String, String>) -> Result<Arc<dyn Plugin>> {
+    let user = params.get("plugin_user").cloned().unwrap_or_default();
+    let passwd = params.get("plugin_passwd").cloned().unwrap_or_default();
+    let auth = (!user.is_empty() || !passwd.is_empty()).then_some((user, passwd));
+
+    Ok(Arc::new(Socks5Plugin { auth }))
+}
+
+impl Plugin for Socks5Plugin {
+    fn name(&self) -> &str {
+        "socks5"
+    }
+
+    fn handle(&self, mut conn: BoxedStream, _meta: StartWorkConn) -> BoxFuture<'_, Result<()>> {
+        async move {
+            self.negotiate(&mut conn).await?;
+            let target = connect(&mut conn).await?;
+            proxy(target, conn).await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+impl Socks5Plugin {
+    async fn negotiate(&self, conn: &mut BoxedStream) -> Result<()> {
+        let mut head = [0; 2];
+        conn.read_exact(&mut head).await?;
+        if head[0] != SOCKS5_VERSION {
+            return Err(anyhow!("unsupported socks version {}", head[0]));
+        }
+        let mut methods = vec![0; head[1] as usize];
+        conn.read_exact(&mut methods).await?;
+
+        let method = match self.auth {
+            Some(_) => SOCKS5_AUTH_PASSWORD,
+            None => SOCKS5_AUTH_NONE,
+        };
+        if !methods.contains(&method) {
+            conn.write_all(&[SOCKS5_VERSION, SOCKS5_AUTH_UNACCEPTABLE])
+                .await?;
+            conn.flush().await?;
+            return Err(anyhow!("socks5 client offers no acceptable auth method"));
+        }
+        // conn may be an encrypted or compressed stream holding the reply back
+        // until flushed, while the client waits for it before going on
+        conn.write_all(&[SOCKS5_VERSION, method]).await?;
+        conn.flush().await?;
+
+        let (user, passwd) = match &self.auth {
+            Some(auth) => auth,
+            None => return Ok(()),
+        };
+        let mut head = [0; 2];
+        conn.read_exact(&mut head).await?;
+        if head[0] != SOCKS5_PASSWORD_VERSION {
+            return Err(anyhow!("unsupported socks5 auth version {}", head[0]));
+        }
+        let mut got_user = vec![0; head[1] as usize];
+        conn.read_exact(&mut got_user).await?;
+        let mut got_passwd = vec![0; read_u8(conn).await? as usize];
+        conn.read_exact(&mut got_passwd).await?;
+
+        if got_user != user.as_bytes() || got_passwd != passwd.as_bytes() {
+            conn.write_all(&[SOCKS5_PASSWORD_VERSION, 0x01]).await?;
+            conn.flush().await?;
+            return Err(anyhow!(
+                "socks5 auth failed for user {}",
+                String::from_utf8_lossy(&got_user)
+            ));
+        }
+        conn.write_all(&[SOCKS5_PASSWORD_VERSION, 0x00]).await?;
+        conn.flush().await?;
+
+        Ok(())
+    }
+}
+
+/// Reads the CONNECT request, dials its target and reports the outcome.
+async fn connect(conn: &mut BoxedStream) -> Result<TcpStream> {
+    let mut req = [0; 4];
+    conn.read_exact(&mut req).await?;
+    if req[0] != SOCKS5_VERSION {
+        return Err(anyhow!("unsupported socks version {}", req[0]));
+    }
+
+    let host = match req[3] {
+        SOCKS5_ATYP_IPV4 => {
+            let mut ip = [0; 4];
+            conn.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        SOCKS5_ATYP_IPV6 => {
+            let mut ip = [0; 16];
+            conn.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        SOCKS5_ATYP_DOMAIN => {
+            let mut domain = vec![0; read_u8(conn).await? as usize];
+            conn.read_exact(&mut domain).await?;
+            String::from_utf8(domain).context("socks5 domain is not utf-8")?
+        }
+        atyp => {
+            reply(conn, SOCKS5_REP_ADDRESS_NOT_SUPPORTED, None).await?;
+            return Err(anyhow!("unsupported socks5 address type {}", atyp));
+        }
+    };
+    let mut port = [0; 2];
+    conn.read_exact(&mut port).await?;
+    let port = u16::from_be_bytes(port);
+
+    if req[1] != SOCKS5_CMD_CONNECT {
+        reply(conn, SOCKS5_REP_COMMAND_NOT_SUPPORTED, None).await?;
+        return Err(anyhow!("unsupported socks5 command {}", req[1]));
+    }
+
+    match TcpStream::connect((host.as_str(), port)).await {
+        Ok(target) => {
+            reply(conn, SOCKS5_REP_SUCCEEDED, target.local_addr().ok()).await?;
+            Ok(target)
+        }
+        Err(e) => {
+            let rep = match e.kind() {
+                io::ErrorKind::ConnectionRefused => SOCKS5_REP_CONNECTION_REFUSED,
+                io::ErrorKind::TimedOut | io::ErrorKind::NotFound => SOCKS5_REP_HOST_UNREACHABLE,
+                _ => SOCKS5_REP_GENERAL_FAILURE,
+            };
+            reply(conn, rep, None).await?;
+            Err(e).with_context(|| format!("socks5 connect to {}:{}", host, port))
+        }
+    }
+}
+
+async fn reply(conn: &mut BoxedStream, rep: u8, bound: Option<SocketAddr>) -> Result<()> {
+    let bound = bound.unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+    let mut resp = vec![SOCKS5_VERSION, rep, 0x00];
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            resp.push(SOCKS5_ATYP_IPV4);
+            resp.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            resp.push(SOCKS5_ATYP_IPV6);
+            resp.extend_from_slice(&ip.octets());
+        }
+    }
+    resp.extend_from_slice(&bound.port().to_be_bytes());
+    conn.write_all(&resp).await?;
+    conn.flush().await?;
+
+    Ok(())
+}
+
+async fn read_u8(conn: &mut BoxedStream) -> Result<u8> {
+    let mut byte = [0; 1];
+    conn.read_exact(&mut byte).await?;
+
+    Ok(byte[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream;
+    use tokio::{io::duplex, net::TcpListener, task::JoinHandle};
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    fn meta() -> StartWorkConn {
+        serde_json::from_value(serde_json::json!({ "proxy_name": "socks" })).unwrap()
+    }
+
+    // runs the plugin on one end of an encrypted pipe small enough that
+    // unflushed replies would never reach the other end
+    fn serve(params: &[(&str, &str)]) -> (BoxedStream, JoinHandle<Result<()>>) {
+        let params = params
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let plugin = build(&params).unwrap();
+        let (client_end, plugin_end) = duplex(8);
+        let plugin_end = stream::with_options(plugin_end.compat(), Some("sk"), false);
+        let handle = tokio::spawn(async move { plugin.handle(plugin_end, meta()).await });
+
+        (
+            stream::with_options(client_end.compat(), Some("sk"), false),
+            handle,
+        )
+    }
+
+    async fn send(conn: &mut BoxedStream, data: &[u8]) {
+        conn.write_all(data).await.unwrap();
+        conn.flush().await.unwrap();
+    }
+
+    async fn recv<const N: usize>(conn: &mut BoxedStream) -> [u8; N] {
+        let mut buf = [0; N];
+        conn.read_exact(&mut buf).await.unwrap();
+        buf
+    }
+
+    async fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut read, mut write) = stream.split();
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+        });
+        addr
+    }
+
+    fn connect_request(cmd: u8, addr: SocketAddr) -> Vec<u8> {
+        let mut req = vec![SOCKS5_VERSION, cmd, 0x00, SOCKS5_ATYP_IPV4];
+        req.extend_from_slice(&[127, 0, 0, 1]);
+        req.extend_from_slice(&addr.port().to_be_bytes());
+        req
+    }
+
+    async fn connect_and_echo(mut conn: BoxedStream, target: SocketAddr) {
+        send(&mut conn, &connect_request(SOCKS5_CMD_CONNECT, target)).await;
+        let resp = recv::<10>(&mut conn).await;
+        assert_eq!(
+            resp[..4],
+            [SOCKS5_VERSION, SOCKS5_REP_SUCCEEDED, 0x00, SOCKS5_ATYP_IPV4]
+        );
+
+        send(&mut conn, b"hello").await;
+        assert_eq!(&recv::<5>(&mut conn).await, b"hello");
+    }
+
+    #[tokio::test]
+    async fn connect_without_auth() {
+        let target = echo_server().await;
+        let (mut conn, _plugin) = serve(&[]);
+
+        send(&mut conn, &[SOCKS5_VERSION, 1, SOCKS5_AUTH_NONE]).await;
+        assert_eq!(
+            recv::<2>(&mut conn).await,
+            [SOCKS5_VERSION, SOCKS5_AUTH_NONE]
+        );
+        connect_and_echo(conn, target).await;
+    }
+
+    #[tokio::test]
+    async fn connect_with_password() {
+        let target = echo_server().await;
+        // one credential is enough to turn auth on
+        let (mut conn, _plugin) = serve(&[("plugin_user", "abc")]);
+
+        // a client that can't authenticate is turned away
+        send(&mut conn, &[SOCKS5_VERSION, 1, SOCKS5_AUTH_NONE]).await;
+        assert_eq!(
+            recv::<2>(&mut conn).await,
+            [SOCKS5_VERSION, SOCKS5_AUTH_UNACCEPTABLE]
+        );
+
+        let (mut conn, _plugin) = serve(&[("plugin_user", "abc")]);
+        send(
+            &mut conn,
+            &[SOCKS5_VERSION, 2, SOCKS5_AUTH_NONE, SOCKS5_AUTH_PASSWORD],
+        )
+        .await;
+        assert_eq!(
+            recv::<2>(&mut conn).await,
+            [SOCKS5_VERSION, SOCKS5_AUTH_PASSWORD]
+        );
+        send(&mut conn, b"\x01\x03abc\x00").await;
+        assert_eq!(recv::<2>(&mut conn).await, [SOCKS5_PASSWORD_VERSION, 0x00]);
+        connect_and_echo(conn, target).await;
+    }
+
+    #[tokio::test]
+    async fn wrong_password_is_rejected() {
+        let (mut conn, plugin) = serve(&[("plugin_user", "abc"), ("plugin_passwd", "123")]);
+
+        send(&mut conn, &[SOCKS5_VERSION, 1, SOCKS5_AUTH_PASSWORD]).await;
+        assert_eq!(
+            recv::<2>(&mut conn).await,
+            [SOCKS5_VERSION, SOCKS5_AUTH_PASSWORD]
+        );
+        send(&mut conn, b"\x01\x03abc\x03456").await;
+        assert_eq!(recv::<2>(&mut conn).await, [SOCKS5_PASSWORD_VERSION, 0x01]);
+
+        let e = plugin.await.unwrap().unwrap_err();
+        assert!(e.to_string().contains("auth failed for user abc"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn unsupported_command() {
+        let (mut conn, plugin) = serve(&[]);
+
+        send(&mut conn, &[SOCKS5_VERSION, 1, SOCKS5_AUTH_NONE]).await;
+        assert_eq!(
+            recv::<2>(&mut conn).await,
+            [SOCKS5_VERSION, SOCKS5_AUTH_NONE]
+        );
+        // UDP ASSOCIATE
+        let addr = SocketAddr::from(([127, 0, 0, 1], 53));
+        send(&mut conn, &connect_request(0x03, addr)).await;
+        let resp = recv::<10>(&mut conn).await;
+        assert_eq!(
+            resp[..2],
+            [SOCKS5_VERSION, SOCKS5_REP_COMMAND_NOT_SUPPORTED]
+        );
+
+        let e = plugin.await.unwrap().unwrap_err();
+        assert!(
+            e.to_string().contains("unsupported socks5 command 3"),
+            "{}",
+            e
+        );
+    }
+}
@@ -0,0 +1,506 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use futures::{future::BoxFuture, FutureExt};
+use hyper::{
+    header::{self, HeaderValue},
+    server::conn::Http,
+    service::service_fn,
+    Body, Method, Request, Response, StatusCode,
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fs::Metadata,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::{compat::FuturesAsyncReadCompatExt, io::ReaderStream};
+
+use super::{http_basic_auth, http_status, Plugin};
+use crate::{msg::StartWorkConn, transport::BoxedStream};
+
+// characters escaped in the links of a directory listing
+const LINK_ESCAPE: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Serves the files under plugin_local_path over HTTP. URLs have to start
+/// with plugin_strip_prefix when it is set, and Basic auth is required when
+/// plugin_http_user or plugin_http_passwd is set.
+#[derive(Debug)]
+pub struct StaticFilePlugin {
+    site: Arc<Site>,
+}
+
+#[derive(Debug)]
+struct Site {
+    root: PathBuf,
+    // always starts and ends with a slash
+    prefix: String,
+    auth: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Whole,
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+pub fn build(params: &HashMap<String, String>) -> Result<Arc<dyn Plugin>> {
+    Ok(Arc::new(StaticFilePlugin {
+        site: Arc::new(Site::new(params)?),
+    }))
+}
+
+impl Plugin for StaticFilePlugin {
+    fn name(&self) -> &str {
+        "static_file"
+    }
+
+    fn handle(&self, conn: BoxedStream, _meta: StartWorkConn) -> BoxFuture<'_, Result<()>> {
+        let site = self.site.clone();
+        let service = service_fn(move |req| {
+            let site = site.clone();
+            async move { Ok::<_, Infallible>(site.serve(req).await) }
+        });
+
+        async move {
+            Http::new()
+                .http1_only(true)
+                .serve_connection(conn.compat(), service)
+                .await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+impl Site {
+    fn new(params: &HashMap<String, String>) -> Result<Self> {
+        let root = params
+            .get("plugin_local_path")
+            .filter(|path| !path.is_empty())
+            .ok_or_else(|| anyhow!("static_file plugin needs plugin_local_path"))?;
+        let prefix = match params
+            .get("plugin_strip_prefix")
+            .map(|p| p.trim_matches('/'))
+        {
+            Some(prefix) if !prefix.is_empty() => format!("/{}/", prefix),
+            _ => "/".to_string(),
+        };
+
+        Ok(Site {
+            root: PathBuf::from(root),
+            prefix,
+            auth: http_basic_auth(params),
+        })
+    }
+
+    async fn serve(&self, req: Request<Body>) -> Response<Body> {
+        if let Some(auth) = &self.auth {
+            let given = req.headers().get(header::AUTHORIZATION);
+            if given.map(HeaderValue::as_bytes) != Some(auth.as_bytes()) {
+                let mut resp = http_status(StatusCode::UNAUTHORIZED);
+                resp.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    HeaderValue::from_static("Basic realm=\"Restricted\""),
+                );
+                return resp;
+            }
+        }
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            let mut resp = http_status(StatusCode::METHOD_NOT_ALLOWED);
+            resp.headers_mut()
+                .insert(header::ALLOW, HeaderValue::from_static("GET, HEAD"));
+            return resp;
+        }
+
+        let url_path = req.uri().path();
+        let rel_path = match url_path.strip_prefix(self.prefix.as_str()) {
+            Some(path) => path,
+            None => return http_status(StatusCode::NOT_FOUND),
+        };
+        let rel_path = match percent_decode_str(rel_path).decode_utf8() {
+            Ok(path) => path,
+            Err(_) => return http_status(StatusCode::BAD_REQUEST),
+        };
+        let mut path = self.root.clone();
+        for segment in rel_path.split('/') {
+            match segment {
+                "" | "." => (),
+                // never leave plugin_local_path
+                ".." => return http_status(StatusCode::BAD_REQUEST),
+                segment => path.push(segment),
+            }
+        }
+
+        let res = match fs::metadata(&path).await {
+            Ok(meta) if meta.is_dir() => {
+                if !url_path.ends_with('/') {
+                    let mut resp = http_status(StatusCode::MOVED_PERMANENTLY);
+                    if let Ok(location) = HeaderValue::from_str(&format!("{}/", url_path)) {
+                        resp.headers_mut().insert(header::LOCATION, location);
+                    }
+                    return resp;
+                }
+                let index = path.join("index.html");
+                match fs::metadata(&index).await {
+                    Ok(meta) if meta.is_file() => serve_file(&req, &index, &meta).await,
+                    _ => list_dir(&path, url_path).await,
+                }
+            }
+            Ok(meta) => serve_file(&req, &path, &meta).await,
+            Err(e) => Err(e),
+        };
+
+        res.unwrap_or_else(|e| match e.kind() {
+            io::ErrorKind::NotFound => http_status(StatusCode::NOT_FOUND),
+            io::ErrorKind::PermissionDenied => http_status(StatusCode::FORBIDDEN),
+            _ => {
+                println!("static_file serve {} error: {}", path.display(), e);
+                http_status(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        })
+    }
+}
+
+async fn serve_file(
+    req: &Request<Body>,
+    path: &Path,
+    meta: &Metadata,
+) -> io::Result<Response<Body>> {
+    let len = meta.len();
+    let range = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .map_or(ByteRange::Whole, |range| parse_range(range, len));
+
+    let mut resp = Response::new(Body::empty());
+    let (start, end) = match range {
+        ByteRange::Whole => (0, len),
+        ByteRange::Part(start, end) => {
+            *resp.status_mut() = StatusCode::PARTIAL_CONTENT;
+            insert_header(
+                &mut resp,
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end - 1, len),
+            );
+            (start, end)
+        }
+        ByteRange::Unsatisfiable => {
+            let mut resp = http_status(StatusCode::RANGE_NOT_SATISFIABLE);
+            insert_header(&mut resp, header::CONTENT_RANGE, format!("bytes */{}", len));
+            return Ok(resp);
+        }
+    };
+
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    insert_header(&mut resp, header::CONTENT_TYPE, mime.to_string());
+    insert_header(&mut resp, header::CONTENT_LENGTH, (end - start).to_string());
+    insert_header(&mut resp, header::ACCEPT_RANGES, "bytes".to_string());
+    if let Ok(modified) = meta.modified() {
+        let modified = DateTime::<Utc>::from(modified);
+        insert_header(
+            &mut resp,
+            header::LAST_MODIFIED,
+            modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+        );
+    }
+
+    if req.method() == Method::GET {
+        let mut file = File::open(path).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        *resp.body_mut() = Body::wrap_stream(ReaderStream::new(file.take(end - start)));
+    }
+
+    Ok(resp)
+}
+
+async fn list_dir(path: &Path, url_path: &str) -> io::Result<Response<Body>> {
+    let mut names = Vec::new();
+    let mut entries = fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let mut name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type().await?.is_dir() {
+            name.push('/');
+        }
+        names.push(name);
+    }
+    names.sort();
+
+    let title = html_escape(url_path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>{}</title></head>\n<body>\n<h1>{}</h1>\n<pre>\n",
+        title, title
+    );
+    for name in names {
+        html.push_str(&format!(
+            "<a href=\"{}\">{}</a>\n",
+            utf8_percent_encode(&name, LINK_ESCAPE),
+            html_escape(&name)
+        ));
+    }
+    html.push_str("</pre>\n</body>\n</html>\n");
+
+    let mut resp = Response::new(Body::from(html));
+    insert_header(
+        &mut resp,
+        header::CONTENT_TYPE,
+        "text/html; charset=utf-8".to_string(),
+    );
+
+    Ok(resp)
+}
+
+/// Reads a `Range: bytes=...` header into a half-open range of the file.
+/// Multiple or malformed ranges are ignored and the whole file is sent, which
+/// HTTP allows.
+fn parse_range(range: &str, len: u64) -> ByteRange {
+    let spec = match range.strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Whole,
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Whole,
+    };
+
+    if first.is_empty() {
+        // bytes=-n asks for the last n bytes
+        return match last.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(n) => ByteRange::Part(len.saturating_sub(n), len),
+            Err(_) => ByteRange::Whole,
+        };
+    }
+    let start = match first.parse::<u64>() {
+        Ok(start) => start,
+        Err(_) => return ByteRange::Whole,
+    };
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    match last {
+        "" => ByteRange::Part(start, len),
+        last => match last.parse::<u64>() {
+            Ok(last) if last >= start => ByteRange::Part(start, (last + 1).min(len)),
+            _ => ByteRange::Whole,
+        },
+    }
+}
+
+fn insert_header(resp: &mut Response<Body>, name: header::HeaderName, value: String) {
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        resp.headers_mut().insert(name, value);
+    }
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn site(root: &Path, params: &[(&str, &str)]) -> Site {
+        let mut params: HashMap<String, String> = params
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        params.insert(
+            "plugin_local_path".to_string(),
+            root.to_str().unwrap().to_string(),
+        );
+        Site::new(&params).unwrap()
+    }
+
+    fn site_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("hello.txt"), "hello world").unwrap();
+        std::fs::create_dir_all(dir.path().join("docs/nested")).unwrap();
+        std::fs::write(dir.path().join("docs/a b.txt"), "").unwrap();
+        std::fs::write(dir.path().join("docs/<x>.css"), "").unwrap();
+        dir
+    }
+
+    async fn get(site: &Site, uri: &str, headers: &[(&str, &str)]) -> (Response<()>, String) {
+        let mut req = Request::get(uri);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let resp = site.serve(req.body(Body::empty()).unwrap()).await;
+        let (parts, body) = resp.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+
+        (
+            Response::from_parts(parts, ()),
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn byte_ranges() {
+        let cases = [
+            ("bytes=0-4", ByteRange::Part(0, 5)),
+            ("bytes=6-", ByteRange::Part(6, 11)),
+            ("bytes=6-100", ByteRange::Part(6, 11)),
+            ("bytes= 3-3 ", ByteRange::Part(3, 4)),
+            // suffix ranges
+            ("bytes=-5", ByteRange::Part(6, 11)),
+            ("bytes=-100", ByteRange::Part(0, 11)),
+            ("bytes=-0", ByteRange::Unsatisfiable),
+            // past the end
+            ("bytes=11-", ByteRange::Unsatisfiable),
+            ("bytes=20-30", ByteRange::Unsatisfiable),
+            // ignored, the whole file is sent
+            ("bytes=5-2", ByteRange::Whole),
+            ("bytes=0-1,4-5", ByteRange::Whole),
+            ("bytes=a-b", ByteRange::Whole),
+            ("bytes=5", ByteRange::Whole),
+            ("lines=0-4", ByteRange::Whole),
+        ];
+        for (range, expect) in cases {
+            assert_eq!(parse_range(range, 11), expect, "{}", range);
+        }
+        assert_eq!(parse_range("bytes=-5", 0), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[tokio::test]
+    async fn serves_files_under_the_prefix() {
+        let dir = site_dir();
+        let site = site(dir.path(), &[("plugin_strip_prefix", "/static/")]);
+
+        let (resp, body) = get(&site, "/static/hello.txt", &[]).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body, "hello world");
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/plain");
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "11");
+        assert_eq!(resp.headers()[header::ACCEPT_RANGES], "bytes");
+
+        let (resp, _) = get(&site, "/hello.txt", &[]).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let (resp, _) = get(&site, "/static/missing.txt", &[]).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let (resp, _) = get(&site, "/static/docs/%3Cx%3E.css", &[]).await;
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/css");
+    }
+
+    #[tokio::test]
+    async fn serves_byte_ranges() {
+        let dir = site_dir();
+        let site = site(dir.path(), &[]);
+
+        let (resp, body) = get(&site, "/hello.txt", &[("range", "bytes=-5")]).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 6-10/11");
+        assert_eq!(body, "world");
+
+        let (resp, body) = get(&site, "/hello.txt", &[("range", "bytes=11-")]).await;
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes */11");
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn lists_directories() {
+        let dir = site_dir();
+        let site = site(dir.path(), &[]);
+
+        let (resp, _) = get(&site, "/docs", &[]).await;
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(resp.headers()[header::LOCATION], "/docs/");
+
+        let (resp, body) = get(&site, "/docs/", &[]).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        let links: Vec<&str> = body.lines().filter(|l| l.starts_with("<a ")).collect();
+        assert_eq!(
+            links,
+            [
+                "<a href=\"%3Cx%3E.css\">&lt;x&gt;.css</a>",
+                "<a href=\"a%20b.txt\">a b.txt</a>",
+                "<a href=\"nested/\">nested/</a>",
+            ]
+        );
+
+        // an index.html is served instead of the listing
+        std::fs::write(dir.path().join("docs/index.html"), "<p>index</p>").unwrap();
+        let (_, body) = get(&site, "/docs/", &[]).await;
+        assert_eq!(body, "<p>index</p>");
+    }
+
+    #[tokio::test]
+    async fn rejects_path_traversal() {
+        let dir = site_dir();
+        let site = site(&dir.path().join("docs"), &[]);
+
+        for uri in [
+            "/../hello.txt",
+            "/nested/../../hello.txt",
+            "/%2e%2e/hello.txt",
+        ] {
+            let (resp, _) = get(&site, uri, &[]).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn basic_auth() {
+        let dir = site_dir();
+        let site = site(
+            dir.path(),
+            &[("plugin_http_user", "abc"), ("plugin_http_passwd", "123")],
+        );
+
+        let (resp, _) = get(&site, "/hello.txt", &[]).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            resp.headers()[header::WWW_AUTHENTICATE],
+            "Basic realm=\"Restricted\""
+        );
+        let (resp, _) = get(
+            &site,
+            "/hello.txt",
+            &[("authorization", "Basic YWJjOjQ1Ng==")],
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let (resp, body) = get(
+            &site,
+            "/hello.txt",
+            &[("authorization", "Basic YWJjOjEyMw==")],
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body, "hello world");
+    }
+}
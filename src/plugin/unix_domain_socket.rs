@@ -0,0 +1,97 @@
+use anyhow::{anyhow, Context, Result};
+use futures::{future::BoxFuture, FutureExt};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use tokio::net::UnixStream;
+
+use super::Plugin;
+use crate::{control::proxy, msg::StartWorkConn, transport::BoxedStream};
+
+/// Forwards each work connection to the unix socket at plugin_unix_path.
+#[derive(Debug)]
+pub struct UnixDomainSocketPlugin {
+    path: PathBuf,
+}
+
+pub fn build(params: &HashMap<String, String>) -> Result<Arc<dyn Plugin>> {
+    let path = params
+        .get("plugin_unix_path")
+        .filter(|path| !path.is_empty())
+        .ok_or_else(|| anyhow!("unix_domain_socket plugin needs plugin_unix_path"))?;
+
+    Ok(Arc::new(UnixDomainSocketPlugin {
+        path: PathBuf::from(path),
+    }))
+}
+
+impl Plugin for UnixDomainSocketPlugin {
+    fn name(&self) -> &str {
+        "unix_domain_socket"
+    }
+
+    fn handle(&self, conn: BoxedStream, _meta: StartWorkConn) -> BoxFuture<'_, Result<()>> {
+        async move {
+            let local_stream = UnixStream::connect(&self.path)
+                .await
+                .with_context(|| format!("connect to {}", self.path.display()))?;
+            proxy(local_stream, conn).await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt},
+        net::UnixListener,
+    };
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut read, mut write) = stream.split();
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+        });
+
+        let params = [(
+            "plugin_unix_path".to_string(),
+            path.to_str().unwrap().to_string(),
+        )]
+        .into();
+        let plugin = build(&params).unwrap();
+        let meta = serde_json::from_value(serde_json::json!({ "proxy_name": "unix" })).unwrap();
+        let (mut user, plugin_end) = duplex(65536);
+        let handle =
+            tokio::spawn(async move { plugin.handle(Box::new(plugin_end.compat()), meta).await });
+
+        user.write_all(b"hello unix").await.unwrap();
+        let mut echo = [0; 10];
+        user.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"hello unix");
+        drop(user);
+        handle.await.unwrap().unwrap();
+
+        // a missing socket is reported with its path
+        let params = [(
+            "plugin_unix_path".to_string(),
+            dir.path().join("gone.sock").to_str().unwrap().to_string(),
+        )]
+        .into();
+        let plugin = build(&params).unwrap();
+        let meta = serde_json::from_value(serde_json::json!({ "proxy_name": "unix" })).unwrap();
+        let (_user, plugin_end) = duplex(65536);
+        let e = plugin
+            .handle(Box::new(plugin_end.compat()), meta)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("gone.sock"), "{}", e);
+    }
+}
@@ -0,0 +1,193 @@
+use anyhow::{anyhow, Error};
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use crate::msg::StartWorkConn;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// version 2 with the PROXY command
+const V2_VERSION_COMMAND: u8 = 0x21;
+const V2_FAMILY_UNSPEC: u8 = 0x00;
+const V2_FAMILY_TCP4: u8 = 0x11;
+const V2_FAMILY_TCP6: u8 = 0x21;
+
+/// The HAProxy PROXY protocol version sent to the local service, set by
+/// proxy_protocol_version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl FromStr for Version {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(Version::V1),
+            "v2" => Ok(Version::V2),
+            _ => Err(anyhow!("unsupported proxy_protocol_version {}", s)),
+        }
+    }
+}
+
+/// Builds the header announcing the user and the frps address it connected to
+/// for a work connection. Addresses frps didn't report come out as an unknown
+/// connection, which the local service then treats as direct.
+pub fn header(version: Version, meta: &StartWorkConn) -> Vec<u8> {
+    let addrs = parse_addr(meta.src_addr(), meta.src_port())
+        .zip(parse_addr(meta.dst_addr(), meta.dst_port()))
+        .map(|(src, dst)| match (src, dst) {
+            (SocketAddr::V4(_), SocketAddr::V4(_)) => (src, dst),
+            // a mixed pair is sent as ipv6, with the ipv4 side mapped
+            _ => (to_ipv6(src), to_ipv6(dst)),
+        });
+
+    match version {
+        Version::V1 => v1_header(addrs),
+        Version::V2 => v2_header(addrs),
+    }
+}
+
+fn v1_header(addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let line = match addrs {
+        Some((src, dst)) => format!(
+            "PROXY {} {} {} {} {}\r\n",
+            if src.is_ipv4() { "TCP4" } else { "TCP6" },
+            src.ip(),
+            dst.ip(),
+            src.port(),
+            dst.port()
+        ),
+        None => "PROXY UNKNOWN\r\n".to_string(),
+    };
+
+    line.into_bytes()
+}
+
+fn v2_header(addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    header.push(V2_VERSION_COMMAND);
+
+    let mut body = Vec::new();
+    let family = match addrs {
+        Some((SocketAddr::V4(src), SocketAddr::V4(dst))) => {
+            body.extend_from_slice(&src.ip().octets());
+            body.extend_from_slice(&dst.ip().octets());
+            V2_FAMILY_TCP4
+        }
+        Some((src, dst)) => {
+            for ip in [src.ip(), dst.ip()] {
+                if let IpAddr::V6(ip) = ip {
+                    body.extend_from_slice(&ip.octets());
+                }
+            }
+            V2_FAMILY_TCP6
+        }
+        None => V2_FAMILY_UNSPEC,
+    };
+    if let Some((src, dst)) = addrs {
+        body.extend_from_slice(&src.port().to_be_bytes());
+        body.extend_from_slice(&dst.port().to_be_bytes());
+    }
+
+    header.push(family);
+    header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    header.extend_from_slice(&body);
+
+    header
+}
+
+fn parse_addr(addr: &str, port: u16) -> Option<SocketAddr> {
+    let ip = addr
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .ok()?;
+
+    Some(SocketAddr::new(ip, port))
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(src: &str, dst: &str) -> StartWorkConn {
+        serde_json::from_value(serde_json::json!({
+            "proxy_name": "web",
+            "src_addr": src,
+            "src_port": 5678,
+            "dst_addr": dst,
+            "dst_port": 6000,
+        }))
+        .unwrap()
+    }
+
+    fn v2(family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21".to_vec();
+        header.push(family);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(body);
+        header
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let header = header(Version::V1, &meta("1.2.3.4", "10.0.0.1"));
+        assert_eq!(header, b"PROXY TCP4 1.2.3.4 10.0.0.1 5678 6000\r\n");
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let tcp6 = header(Version::V1, &meta("[2001:db8::1]", "2001:db8::2"));
+        assert_eq!(tcp6, b"PROXY TCP6 2001:db8::1 2001:db8::2 5678 6000\r\n");
+
+        let mixed = header(Version::V1, &meta("1.2.3.4", "2001:db8::2"));
+        assert_eq!(
+            mixed,
+            b"PROXY TCP6 ::ffff:1.2.3.4 2001:db8::2 5678 6000\r\n"
+        );
+    }
+
+    #[test]
+    fn v1_unknown() {
+        let header = header(Version::V1, &meta("", "10.0.0.1"));
+        assert_eq!(header, b"PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn v2_tcp4() {
+        let header = header(Version::V2, &meta("1.2.3.4", "10.0.0.1"));
+        let body = [1, 2, 3, 4, 10, 0, 0, 1, 0x16, 0x2e, 0x17, 0x70];
+        assert_eq!(header, v2(0x11, &body));
+        assert_eq!(header.len(), 16 + 12);
+    }
+
+    #[test]
+    fn v2_mixed_families_as_tcp6() {
+        let header = header(Version::V2, &meta("1.2.3.4", "2001:db8::2"));
+        let mut body = vec![0; 10];
+        body.extend_from_slice(&[0xff, 0xff, 1, 2, 3, 4]);
+        body.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        body.extend_from_slice(&[0; 11]);
+        body.push(2);
+        body.extend_from_slice(&[0x16, 0x2e, 0x17, 0x70]);
+        assert_eq!(header, v2(0x21, &body));
+        assert_eq!(header.len(), 16 + 36);
+    }
+
+    #[test]
+    fn v2_unspec_without_addresses() {
+        let header = header(Version::V2, &meta("1.2.3.4", ""));
+        assert_eq!(header, v2(0x00, &[]));
+    }
+}
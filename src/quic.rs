@@ -0,0 +1,90 @@
+use anyhow::{Context as _, Result};
+use quinn::{ClientConfig, Connection, Endpoint, RecvStream, SendStream, TransportConfig};
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{config::Config, dialer, tls};
+
+// same values frp passes to quic-go
+const QUIC_MAX_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const QUIC_KEEP_ALIVE_PERIOD: Duration = Duration::from_secs(10);
+const QUIC_ALPN: &[u8] = b"frp";
+
+/// Opens the QUIC connection to frps's quic_bind_port. The certificate
+/// options only apply with tls_enable, otherwise the server is not verified,
+/// like in frp.
+pub async fn connect(address: SocketAddr, cfg: &Config) -> Result<Connection> {
+    let mut tls_config = if cfg.tls_enable() {
+        tls::client_config(
+            cfg.tls_cert_file(),
+            cfg.tls_key_file(),
+            cfg.tls_trusted_ca_file(),
+        )?
+    } else {
+        tls::client_config("", "", "")?
+    };
+    tls_config.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+
+    let mut transport = TransportConfig::default();
+    transport.max_idle_timeout(Some(QUIC_MAX_IDLE_TIMEOUT.try_into()?));
+    transport.keep_alive_interval(Some(QUIC_KEEP_ALIVE_PERIOD));
+    let mut client_config = ClientConfig::new(Arc::new(tls_config));
+    client_config.transport_config(Arc::new(transport));
+
+    let mut endpoint = Endpoint::client(dialer::local_addr(cfg, address)?)?;
+    endpoint.set_default_client_config(client_config);
+    let conn = endpoint
+        .connect(address, tls::server_name(cfg))?
+        .await
+        .with_context(|| format!("quic connect to server {}", address))?;
+
+    Ok(conn)
+}
+
+/// Opens a new bidirectional stream, which frps treats as one connection.
+pub async fn open_stream(conn: &Connection) -> Result<QuicStream> {
+    let (send, recv) = conn.open_bi().await?;
+
+    Ok(QuicStream { send, recv })
+}
+
+/// Both halves of a QUIC stream as one tokio stream.
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
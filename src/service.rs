@@ -1,72 +1,384 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Result};
 use futures::{channel::mpsc, prelude::*};
-use std::{net::ToSocketAddrs, process};
-use tokio::{net::TcpSocket, runtime::Runtime, task};
-use tokio_util::compat::TokioAsyncReadCompatExt;
-use yamux::{Config as YamuxConfig, Connection, Control, Mode, Stream, WindowUpdateMode};
+use rand::Rng;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{runtime::Runtime, time::sleep};
 
 use crate::{
     config::Config,
     control::Control as FrpControl,
     msg::{Login, LoginResp},
+    plugin::{self, Plugin},
+    transport::{self, BoxedStream, StreamOpener},
 };
 
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(20);
+
+/// Connects the transport for a new session, `transport::open` outside tests.
+type Connect =
+    Box<dyn Fn(Config) -> future::BoxFuture<'static, Result<Arc<dyn StreamOpener>>> + Send + Sync>;
+
 #[derive(Debug, Clone)]
 pub struct Service {
-    pub main_ctl: Control,
+    pub opener: Arc<dyn StreamOpener>,
     pub run_id: String,
+    pub server_udp_port: u16,
+    pub plugins: HashMap<String, Arc<dyn Plugin>>,
     pub cfg: Config,
 }
 
 impl Service {
-    pub async fn new(cfg: Config) -> Result<Self> {
-        let conn = {
-            let mut yamux_cfg = YamuxConfig::default();
-            yamux_cfg.set_split_send_size(crate::PAYLOAD_SIZE);
-            yamux_cfg.set_window_update_mode(WindowUpdateMode::OnRead);
-
-            let address = format!("{}:{}", cfg.server_addr(), cfg.server_port())
-                .to_socket_addrs()?
-                .next()
-                .unwrap();
-            let socket = TcpSocket::new_v4().expect("new_v4");
-            let stream = socket.connect(address).await.expect("connect").compat();
-            Connection::new(stream, yamux_cfg, Mode::Client)
-        };
-        let mut ctrl = conn.control();
-        task::spawn(yamux::into_stream(conn).for_each(|_| future::ready(())));
-
-        Ok(Self {
-            main_ctl: ctrl,
+    /// A service on a connected transport. `plugins` are the proxies'
+    /// plugins, built once up front by `plugin::create_all`.
+    fn with_opener(
+        opener: Arc<dyn StreamOpener>,
+        cfg: Config,
+        plugins: HashMap<String, Arc<dyn Plugin>>,
+    ) -> Self {
+        Self {
+            opener,
             run_id: "".to_string(),
+            server_udp_port: 0,
+            plugins,
             cfg,
-        })
+        }
     }
 
-    pub async fn run(&mut self) -> Result<()> {
-        let mut main_stream = self.main_ctl.open_stream().await.unwrap();
-        let login = Login::new(&self.cfg);
-        let login_resp = login.send_msg(&mut main_stream).await.unwrap();
+    /// Opens a new stream to frps for the control, work or visitor connection.
+    pub async fn open_stream(&self) -> Result<BoxedStream> {
+        self.opener.open_stream().await
+    }
+
+    pub async fn login(&mut self) -> Result<(BoxedStream, [u8; 16])> {
+        let mut main_stream = self.open_stream().await?;
+        let mut login = Login::new(&self.cfg);
+        login.set_run_id(&self.run_id);
+        let login_resp = login.send_msg(&mut main_stream).await?;
         println!("login_resp {:?}", login_resp);
         if !login_resp.error().is_empty() {
-            println!("app exit {}", login_resp.error());
-            process::exit(1);
+            return Err(anyhow!("login to server failed: {}", login_resp.error()));
+        }
+        if login_resp.run_id().is_empty() {
+            return Err(anyhow!("login response without run_id"));
         }
-        assert_eq!(login_resp.run_id().is_empty(), false);
         self.run_id = login_resp.run_id().to_string();
+        self.server_udp_port = login_resp.server_udp_port();
 
         // read iv[16]
         let mut iv = [0; 16];
         main_stream.read_exact(&mut iv).await?;
         println!("iv {:?}", iv);
 
-        let mut frp_ctl = FrpControl::new(self.clone(), iv);
-        frp_ctl.run(&mut main_stream).await?;
+        Ok((main_stream, iv))
+    }
 
-        Ok(())
+    pub async fn close(&mut self) {
+        self.opener.close().await
     }
 
     pub fn get_conf(&self) -> &Config {
         &self.cfg
     }
 }
+
+/// Keeps a control session to frps alive: whenever the session dies the
+/// server is dialed again, login is redone and all proxies are registered
+/// again, waiting a jittered exponential backoff between attempts.
+pub struct Supervisor {
+    cfg: Config,
+    plugins: HashMap<String, Arc<dyn Plugin>>,
+    run_id: String,
+    delay: Duration,
+    connect: Connect,
+}
+
+impl Supervisor {
+    /// Builds the proxies' plugins, so a bad plugin configuration fails
+    /// startup instead of every connection attempt.
+    pub fn new(cfg: Config) -> Result<Self> {
+        Ok(Self {
+            plugins: plugin::create_all(&cfg)?,
+            cfg,
+            run_id: "".to_string(),
+            delay: RECONNECT_MIN_DELAY,
+            connect: Box::new(|cfg| async move { transport::open(&cfg).await }.boxed()),
+        })
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        let mut logged_in = false;
+
+        loop {
+            let mut service = match (self.connect)(self.cfg.clone()).await {
+                Ok(opener) => Service::with_opener(opener, self.cfg.clone(), self.plugins.clone()),
+                Err(e) => {
+                    if !logged_in && self.cfg.login_fail_exit() {
+                        return Err(e);
+                    }
+                    println!("connect to server error: {:#}", e);
+                    self.backoff().await;
+                    continue;
+                }
+            };
+            service.run_id = self.run_id.clone();
+
+            let (mut main_stream, iv) = match service.login().await {
+                Ok(res) => res,
+                Err(e) => {
+                    service.close().await;
+                    if !logged_in && self.cfg.login_fail_exit() {
+                        return Err(e);
+                    }
+                    println!("login error: {:#}", e);
+                    self.backoff().await;
+                    continue;
+                }
+            };
+            logged_in = true;
+            self.run_id = service.run_id.clone();
+            self.delay = RECONNECT_MIN_DELAY;
+
+            let mut frp_ctl = FrpControl::new(service.clone(), iv);
+            if let Err(e) = frp_ctl.run(&mut main_stream).await {
+                println!("control session closed: {:#}", e);
+            }
+            service.close().await;
+            self.backoff().await;
+        }
+    }
+
+    async fn backoff(&mut self) {
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(RECONNECT_MAX_DELAY);
+
+        // sleep somewhere in [delay/2, delay] so clients don't reconnect in lockstep
+        let half = delay.as_millis() as u64 / 2;
+        let jittered = Duration::from_millis(half + rand::thread_rng().gen_range(0..=half));
+        println!("reconnect to server in {:?}", jittered);
+        sleep(jittered).await;
+    }
+}
+
+/// Hands out prepared streams in place of connections to frps.
+#[cfg(test)]
+struct TestOpener(std::sync::Mutex<Vec<BoxedStream>>);
+
+#[cfg(test)]
+impl std::fmt::Debug for TestOpener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TestOpener")
+    }
+}
+
+#[cfg(test)]
+impl TestOpener {
+    fn new(mut streams: Vec<BoxedStream>) -> Self {
+        streams.reverse();
+        TestOpener(std::sync::Mutex::new(streams))
+    }
+}
+
+#[cfg(test)]
+impl StreamOpener for TestOpener {
+    fn open_stream(&self) -> future::BoxFuture<'_, Result<BoxedStream>> {
+        let stream = self.0.lock().unwrap().pop();
+        Box::pin(async { stream.ok_or_else(|| anyhow!("no more test streams")) })
+    }
+
+    fn close(&self) -> future::BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
+}
+
+#[cfg(test)]
+impl Service {
+    /// A service whose `open_stream` returns `streams` in order.
+    pub(crate) fn with_streams(cfg: Config, streams: Vec<BoxedStream>) -> Self {
+        Self::with_opener(Arc::new(TestOpener::new(streams)), cfg, HashMap::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::{read_msg, write_msg, Message};
+    use tokio::{io::duplex, time::Instant};
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    #[tokio::test]
+    async fn login_reuses_run_id_and_reads_iv() {
+        let (client_end, server_end) = duplex(4096);
+        let mut service = Service::with_streams(Config::new(), vec![Box::new(client_end.compat())]);
+        service.run_id = "previous".to_string();
+
+        let frps = tokio::spawn(async move {
+            let mut main_stream = server_end.compat();
+            match read_msg(&mut main_stream).await.unwrap() {
+                Message::Login(login) => {
+                    assert_eq!(serde_json::to_value(login).unwrap()["run_id"], "previous");
+                }
+                msg => panic!("expect login, got {:?}", msg.msg_type()),
+            }
+            let resp = serde_json::from_value(serde_json::json!({
+                "version": crate::FRP_VERSION,
+                "run_id": "next",
+                "server_udp_port": 7001,
+            }))
+            .unwrap();
+            write_msg(&mut main_stream, &Message::LoginResp(resp))
+                .await
+                .unwrap();
+            main_stream.write_all(&[7; 16]).await.unwrap();
+            main_stream
+        });
+
+        let (_main_stream, iv) = service.login().await.unwrap();
+        assert_eq!(iv, [7; 16]);
+        assert_eq!(service.run_id, "next");
+        assert_eq!(service.server_udp_port, 7001);
+        frps.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn login_error_is_reported() {
+        let (client_end, server_end) = duplex(4096);
+        let mut service = Service::with_streams(Config::new(), vec![Box::new(client_end.compat())]);
+
+        tokio::spawn(async move {
+            let mut main_stream = server_end.compat();
+            read_msg(&mut main_stream).await.unwrap();
+            let resp = serde_json::from_value(serde_json::json!({
+                "error": "token in login doesn't match token from configuration",
+            }))
+            .unwrap();
+            write_msg(&mut main_stream, &Message::LoginResp(resp))
+                .await
+                .unwrap();
+            main_stream
+        });
+
+        let e = service.login().await.err().unwrap();
+        assert!(e.to_string().contains("token in login"), "{}", e);
+    }
+
+    #[test]
+    fn supervisor_builds_plugins_up_front() {
+        let mut cfg = Config::new();
+        let content = "[socks]\ntype = tcp\nremote_port = 6000\nplugin = socks5\n";
+        cfg.load_config_str(content).unwrap();
+        let supervisor = Supervisor::new(cfg).unwrap();
+        assert_eq!(supervisor.plugins["socks"].name(), "socks5");
+
+        let mut cfg = Config::new();
+        let content = "[web]\ntype = tcp\nremote_port = 6000\nplugin = socks4\n";
+        cfg.load_config_str(content).unwrap();
+        let e = Supervisor::new(cfg).err().unwrap();
+        assert!(format!("{:#}", e).contains("[web]"), "{:#}", e);
+        assert!(
+            format!("{:#}", e).contains("unknown plugin socks4"),
+            "{:#}",
+            e
+        );
+    }
+
+    // stands in for frps on one session: answers the login, checking the
+    // run_id frpc sends, then hangs up
+    fn frps_session(expect_run_id: &'static str) -> BoxedStream {
+        let (client_end, server_end) = duplex(4096);
+        tokio::spawn(async move {
+            let mut main_stream = server_end.compat();
+            match read_msg(&mut main_stream).await.unwrap() {
+                Message::Login(login) => {
+                    let login = serde_json::to_value(login).unwrap();
+                    assert_eq!(login["run_id"].as_str().unwrap_or(""), expect_run_id);
+                }
+                msg => panic!("expect login, got {:?}", msg.msg_type()),
+            }
+            let resp = serde_json::from_value(serde_json::json!({
+                "version": crate::FRP_VERSION,
+                "run_id": "run-1",
+            }))
+            .unwrap();
+            write_msg(&mut main_stream, &Message::LoginResp(resp))
+                .await
+                .unwrap();
+            main_stream.write_all(&[0; 16]).await.unwrap();
+        });
+
+        Box::new(client_end.compat())
+    }
+
+    // a supervisor whose connection attempts take the scripted sessions in
+    // order, None failing to connect, and report when they were made
+    fn scripted(
+        cfg: &str,
+        sessions: Vec<Option<BoxedStream>>,
+    ) -> (Supervisor, mpsc::UnboundedReceiver<Instant>) {
+        let mut config = Config::new();
+        config.load_config_str(cfg).unwrap();
+        let mut supervisor = Supervisor::new(config).unwrap();
+        let sessions = std::sync::Mutex::new(sessions.into_iter());
+        let (tx, rx) = mpsc::unbounded();
+        supervisor.connect = Box::new(move |_| {
+            tx.unbounded_send(Instant::now()).unwrap();
+            let session = sessions.lock().unwrap().next().flatten();
+            async move {
+                let stream = session.ok_or_else(|| anyhow!("connection refused"))?;
+                Ok(Arc::new(TestOpener::new(vec![stream])) as Arc<dyn StreamOpener>)
+            }
+            .boxed()
+        });
+
+        (supervisor, rx)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_doubles_up_to_the_cap_and_resets_after_login() {
+        // login_fail_exit is on by default, but only the first login counts
+        let mut sessions = vec![Some(frps_session(""))];
+        sessions.extend((0..6).map(|_| None));
+        sessions.push(Some(frps_session("run-1")));
+        let (mut supervisor, mut attempts) = scripted("[common]\n", sessions);
+        let supervisor = tokio::spawn(async move { supervisor.run().await });
+
+        let mut last = attempts.next().await.unwrap();
+        // (min, max) seconds before each next attempt
+        let expected = [
+            (0.5, 1.0),
+            (1.0, 2.0),
+            (2.0, 4.0),
+            (4.0, 8.0),
+            (8.0, 16.0),
+            (10.0, 20.0),
+            (10.0, 20.0),
+            // logged in again with run-1, back to the minimum
+            (0.5, 1.0),
+        ];
+        for (i, (min, max)) in expected.into_iter().enumerate() {
+            let next = attempts.next().await.unwrap();
+            let gap = (next - last).as_secs_f64();
+            assert!(min <= gap && gap <= max, "attempt {}: {}s", i + 2, gap);
+            last = next;
+        }
+        assert!(!supervisor.is_finished());
+        supervisor.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn login_fail_exit_before_the_first_login() {
+        let (mut supervisor, _attempts) = scripted("[common]\n", vec![None]);
+        let e = supervisor.run().await.unwrap_err();
+        assert!(e.to_string().contains("connection refused"), "{}", e);
+
+        // with login_fail_exit off the client keeps retrying
+        let (mut supervisor, mut attempts) =
+            scripted("[common]\nlogin_fail_exit = false\n", vec![None, None]);
+        let supervisor = tokio::spawn(async move { supervisor.run().await });
+        for _ in 0..3 {
+            attempts.next().await.unwrap();
+        }
+        assert!(!supervisor.is_finished());
+        supervisor.abort();
+    }
+}
@@ -0,0 +1,495 @@
+use futures::io::{AsyncRead, AsyncWrite};
+use rand::RngCore;
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::{
+    crypto::FrpCoder,
+    transport::{BoxedStream, ServerStream},
+};
+
+// snappy framing format, as written by golang/snappy
+const SNAPPY_STREAM_IDENTIFIER: &[u8] = b"\xff\x06\x00\x00sNaPpY";
+const SNAPPY_CHUNK_COMPRESSED: u8 = 0x00;
+const SNAPPY_CHUNK_UNCOMPRESSED: u8 = 0x01;
+const SNAPPY_CHUNK_STREAM_IDENTIFIER: u8 = 0xff;
+const SNAPPY_MAX_BLOCK_SIZE: usize = 65536;
+const SNAPPY_READ_SIZE: usize = 16 * 1024;
+
+/// Wraps a work or visitor stream the way frp does for a proxy's
+/// `use_encryption` and `use_compression`: data is compressed first and the
+/// compressed stream is encrypted.
+pub fn with_options<S>(
+    stream: S,
+    encryption_key: Option<&str>,
+    use_compression: bool,
+) -> BoxedStream
+where
+    S: ServerStream + 'static,
+{
+    let mut stream: BoxedStream = Box::new(stream);
+    if let Some(key) = encryption_key {
+        stream = Box::new(EncryptedStream::new(stream, key));
+    }
+    if use_compression {
+        stream = Box::new(CompressedStream::new(stream));
+    }
+
+    stream
+}
+
+/// AES-128-CFB wrapper for work and visitor connections, compatible with
+/// frp's `crypto.NewReader` / `crypto.NewWriter`: each direction starts with
+/// the plain 16 byte iv chosen by its writer, the key is derived the same way
+/// as for the control stream.
+pub struct EncryptedStream<S> {
+    inner: S,
+    key: String,
+    // read side, the coder is created once the peer's iv is complete
+    dec: Option<FrpCoder>,
+    peer_iv: [u8; 16],
+    peer_iv_len: usize,
+    // write side, encrypted bytes not yet accepted by `inner`
+    enc: FrpCoder,
+    pending: Vec<u8>,
+}
+
+impl<S> EncryptedStream<S> {
+    pub fn new(inner: S, key: &str) -> Self {
+        let mut iv = [0; 16];
+        rand::thread_rng().fill_bytes(&mut iv);
+        let enc = FrpCoder::new(key, iv);
+
+        Self {
+            inner,
+            key: key.to_string(),
+            dec: None,
+            peer_iv: [0; 16],
+            peer_iv_len: 0,
+            pending: iv.to_vec(),
+            enc,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> EncryptedStream<S> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = match Pin::new(&mut self.inner).poll_write(cx, &self.pending) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            self.pending.drain(..n);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for EncryptedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        while this.dec.is_none() {
+            let iv_buf = &mut this.peer_iv[this.peer_iv_len..];
+            let n = match Pin::new(&mut this.inner).poll_read(cx, iv_buf) {
+                Poll::Ready(Ok(n)) => n,
+                other => return other,
+            };
+            if n == 0 {
+                return match this.peer_iv_len {
+                    0 => Poll::Ready(Ok(0)),
+                    _ => Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                };
+            }
+            this.peer_iv_len += n;
+            if this.peer_iv_len == this.peer_iv.len() {
+                this.dec = Some(FrpCoder::new(&this.key, this.peer_iv));
+            }
+        }
+
+        let n = match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(n)) => n,
+            other => return other,
+        };
+        if let Some(dec) = &mut this.dec {
+            dec.decrypt(&mut buf[..n])
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for EncryptedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.poll_pending(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+
+        // the cipher state has moved on once data is encrypted, so it is
+        // accepted right away and drained by later writes or flush
+        this.pending.extend_from_slice(buf);
+        this.enc
+            .encypt(&mut this.pending)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let _ = this.poll_pending(cx)?;
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.poll_pending(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.poll_pending(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+/// Snappy framed compression, compatible with the golang/snappy reader and
+/// unbuffered writer frp uses: every write becomes complete chunks on the
+/// wire, so nothing waits for a flush.
+pub struct CompressedStream<S> {
+    inner: S,
+    encoder: snap::raw::Encoder,
+    decoder: snap::raw::Decoder,
+    // read side, raw chunk bytes and the decoded data not yet returned
+    chunk: Vec<u8>,
+    plain: Vec<u8>,
+    plain_pos: usize,
+    // write side, chunks not yet accepted by `inner`
+    pending: Vec<u8>,
+}
+
+impl<S> CompressedStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            encoder: snap::raw::Encoder::new(),
+            decoder: snap::raw::Decoder::new(),
+            chunk: Vec::new(),
+            plain: Vec::new(),
+            plain_pos: 0,
+            pending: SNAPPY_STREAM_IDENTIFIER.to_vec(),
+        }
+    }
+
+    fn encode_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        let compressed = self
+            .encoder
+            .compress_vec(data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // same rule as golang/snappy: keep the data as is unless it shrinks by 1/8
+        let (chunk_type, body) = if compressed.len() < data.len() - data.len() / 8 {
+            (SNAPPY_CHUNK_COMPRESSED, &compressed[..])
+        } else {
+            (SNAPPY_CHUNK_UNCOMPRESSED, data)
+        };
+
+        let len = (body.len() + 4) as u32;
+        self.pending.push(chunk_type);
+        self.pending.extend_from_slice(&len.to_le_bytes()[..3]);
+        self.pending
+            .extend_from_slice(&masked_crc(data).to_le_bytes());
+        self.pending.extend_from_slice(body);
+
+        Ok(())
+    }
+
+    /// Decodes the first chunk of `self.chunk` if it is complete, returning
+    /// whether one was consumed.
+    fn decode_chunk(&mut self) -> io::Result<bool> {
+        if self.chunk.len() < 4 {
+            return Ok(false);
+        }
+        let chunk_type = self.chunk[0];
+        let len = u32::from_le_bytes([self.chunk[1], self.chunk[2], self.chunk[3], 0]) as usize;
+        if self.chunk.len() < 4 + len {
+            return Ok(false);
+        }
+
+        let body = &self.chunk[4..4 + len];
+        match chunk_type {
+            SNAPPY_CHUNK_STREAM_IDENTIFIER if body != &SNAPPY_STREAM_IDENTIFIER[4..] => {
+                return Err(corrupt("bad stream identifier"));
+            }
+            SNAPPY_CHUNK_STREAM_IDENTIFIER => (),
+            SNAPPY_CHUNK_COMPRESSED | SNAPPY_CHUNK_UNCOMPRESSED => {
+                if len < 4 {
+                    return Err(corrupt("chunk too short"));
+                }
+                let crc = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
+                let data = if chunk_type == SNAPPY_CHUNK_COMPRESSED {
+                    self.decoder
+                        .decompress_vec(&body[4..])
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+                } else {
+                    body[4..].to_vec()
+                };
+                if data.len() > SNAPPY_MAX_BLOCK_SIZE {
+                    return Err(corrupt("chunk exceeds block size"));
+                }
+                if masked_crc(&data) != crc {
+                    return Err(corrupt("crc mismatch"));
+                }
+                self.plain = data;
+                self.plain_pos = 0;
+            }
+            // reserved unskippable chunks
+            0x02..=0x7f => return Err(corrupt("unsupported chunk type")),
+            // padding and reserved skippable chunks
+            _ => (),
+        }
+        self.chunk.drain(..4 + len);
+
+        Ok(true)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> CompressedStream<S> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = match Pin::new(&mut self.inner).poll_write(cx, &self.pending) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            self.pending.drain(..n);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CompressedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        loop {
+            if this.plain_pos < this.plain.len() {
+                let n = buf.len().min(this.plain.len() - this.plain_pos);
+                buf[..n].copy_from_slice(&this.plain[this.plain_pos..this.plain_pos + n]);
+                this.plain_pos += n;
+                return Poll::Ready(Ok(n));
+            }
+            if this.decode_chunk()? {
+                continue;
+            }
+
+            let mut read_buf = [0; SNAPPY_READ_SIZE];
+            let n = match Pin::new(&mut this.inner).poll_read(cx, &mut read_buf) {
+                Poll::Ready(Ok(n)) => n,
+                other => return other,
+            };
+            if n == 0 {
+                return match this.chunk.is_empty() {
+                    true => Poll::Ready(Ok(0)),
+                    false => Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                };
+            }
+            this.chunk.extend_from_slice(&read_buf[..n]);
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CompressedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.poll_pending(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+
+        let n = buf.len().min(SNAPPY_MAX_BLOCK_SIZE);
+        if n > 0 {
+            this.encode_chunk(&buf[..n])?;
+        }
+        let _ = this.poll_pending(cx)?;
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.poll_pending(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.poll_pending(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+fn masked_crc(data: &[u8]) -> u32 {
+    let crc = crc32c::crc32c(data);
+    crc.rotate_right(15).wrapping_add(0xa282ead8)
+}
+
+fn corrupt(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt snappy stream: {}", reason),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::{AsyncReadExt, AsyncWriteExt, Cursor};
+    use std::io::{Read, Write};
+    use tokio::io::duplex;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    // compressible text followed by random bytes, more than one snappy block
+    fn data() -> Vec<u8> {
+        let mut data: Vec<u8> = (0..200_000).map(|i| b"frp work conn "[i % 14]).collect();
+        let mut random = vec![0; 70_000];
+        rand::thread_rng().fill_bytes(&mut random);
+        data.extend_from_slice(&random);
+        data
+    }
+
+    async fn round_trip<A, B>(mut a: A, mut b: B)
+    where
+        A: AsyncRead + AsyncWrite + Unpin,
+        B: AsyncRead + AsyncWrite + Unpin,
+    {
+        let data = data();
+        let write = async {
+            a.write_all(&data).await.unwrap();
+            a.close().await.unwrap();
+        };
+        let read = async {
+            let mut received = Vec::new();
+            b.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        let (_, received) = tokio::join!(write, read);
+        assert!(received == data);
+    }
+
+    #[tokio::test]
+    async fn encrypted_round_trip() {
+        let (a, b) = duplex(4096);
+        let a = EncryptedStream::new(a.compat(), "key");
+        let b = EncryptedStream::new(b.compat(), "key");
+        round_trip(a, b).await;
+    }
+
+    #[tokio::test]
+    async fn encrypted_wire_format() {
+        let (a, mut b) = (Vec::new(), Vec::new());
+        let mut a = EncryptedStream::new(Cursor::new(a), "key");
+        a.write_all(b"hello frps").await.unwrap();
+        b.extend_from_slice(a.inner.get_ref());
+
+        // the writer's iv in plain text, then AES-128-CFB with frp's derived key
+        let mut coder = FrpCoder::new("key", b[..16].try_into().unwrap());
+        coder.decrypt(&mut b[16..]).unwrap();
+        assert_eq!(&b[16..], b"hello frps");
+    }
+
+    #[tokio::test]
+    async fn compressed_round_trip() {
+        let (a, b) = duplex(4096);
+        round_trip(
+            CompressedStream::new(a.compat()),
+            CompressedStream::new(b.compat()),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn compressed_stream_reads_snap_frames() {
+        let data = data();
+        let mut encoder = snap::write::FrameEncoder::new(Vec::new());
+        encoder.write_all(&data).unwrap();
+        let wire = encoder.into_inner().unwrap();
+
+        let mut stream = CompressedStream::new(Cursor::new(wire));
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert!(received == data);
+    }
+
+    #[tokio::test]
+    async fn snap_reads_compressed_stream() {
+        let data = data();
+        let mut stream = CompressedStream::new(Cursor::new(Vec::new()));
+        for chunk in data.chunks(10_000) {
+            stream.write_all(chunk).await.unwrap();
+        }
+        let wire = stream.inner.into_inner();
+
+        let mut received = Vec::new();
+        snap::read::FrameDecoder::new(&wire[..])
+            .read_to_end(&mut received)
+            .unwrap();
+        assert!(received == data);
+    }
+
+    // frp compresses first and encrypts the compressed stream
+    #[tokio::test]
+    async fn options_compress_then_encrypt() {
+        let data = data();
+        let (a, b) = duplex(4096);
+        let mut a = with_options(a.compat(), Some("key"), true);
+        let mut b = EncryptedStream::new(b.compat(), "key");
+        let write = async {
+            a.write_all(&data).await.unwrap();
+            a.close().await.unwrap();
+        };
+        let read = async {
+            let mut compressed = Vec::new();
+            b.read_to_end(&mut compressed).await.unwrap();
+            compressed
+        };
+        let (_, compressed) = tokio::join!(write, read);
+
+        assert!(compressed.starts_with(SNAPPY_STREAM_IDENTIFIER));
+        let mut received = Vec::new();
+        snap::read::FrameDecoder::new(&compressed[..])
+            .read_to_end(&mut received)
+            .unwrap();
+        assert!(received == data);
+    }
+}
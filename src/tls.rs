@@ -0,0 +1,296 @@
+use anyhow::{anyhow, Context, Result};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName,
+};
+use std::{fs::File, io::BufReader, sync::Arc, time::SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use crate::config::Config;
+
+// frps tells tls apart from plain connections on the same port by this first byte
+const FRP_TLS_HEAD_BYTE: u8 = 0x17;
+
+/// Runs the tls handshake on a fresh connection to frps. `head_byte` sends
+/// frp's marker first, which only frps itself understands; it is left out when
+/// the connection may end at an ordinary https endpoint such as a websocket proxy.
+pub async fn connect<S>(mut stream: S, cfg: &Config, head_byte: bool) -> Result<TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let tls_config = client_config(
+        cfg.tls_cert_file(),
+        cfg.tls_key_file(),
+        cfg.tls_trusted_ca_file(),
+    )?;
+    let connector = TlsConnector::from(Arc::new(tls_config));
+    let name = server_name(cfg);
+    let server_name =
+        ServerName::try_from(name).map_err(|_| anyhow!("invalid tls server name {}", name))?;
+
+    if head_byte {
+        stream.write_all(&[FRP_TLS_HEAD_BYTE]).await?;
+    }
+    let stream = connector
+        .connect(server_name, stream)
+        .await
+        .context("tls handshake with server")?;
+
+    Ok(stream)
+}
+
+/// The name the server certificate is checked against, the server address
+/// unless tls_server_name is set.
+pub fn server_name(cfg: &Config) -> &str {
+    match cfg.tls_server_name() {
+        "" => cfg.server_addr(),
+        name => name,
+    }
+}
+
+/// Builds a client config the way frp's `NewClientTLSConfig` does: a client
+/// certificate is presented when both files are given and, as in frp, the
+/// server certificate is only verified when a trusted CA is configured.
+pub fn client_config(cert_file: &str, key_file: &str, ca_file: &str) -> Result<ClientConfig> {
+    let verifier: Arc<dyn ServerCertVerifier> = if ca_file.is_empty() {
+        Arc::new(NoVerification)
+    } else {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(ca_file)? {
+            roots
+                .add(&cert)
+                .map_err(|e| anyhow!("invalid trusted CA certificate: {:?}", e))?;
+        }
+        Arc::new(WebPkiVerifier::new(roots, None))
+    };
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(verifier);
+    let config = if !cert_file.is_empty() && !key_file.is_empty() {
+        builder.with_single_cert(load_certs(cert_file)?, load_key(key_file)?)?
+    } else {
+        builder.with_no_client_auth()
+    };
+
+    Ok(config)
+}
+
+/// Builds a server config from a certificate chain and its private key, for
+/// terminating tls in frpc itself.
+pub fn server_config(cert_file: &str, key_file: &str) -> Result<ServerConfig> {
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert_file)?, load_key(key_file)?)?;
+
+    Ok(config)
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path).with_context(|| format!("open {}", path))?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate in {}", path));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path).with_context(|| format!("open {}", path))?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => (),
+        }
+    }
+
+    Err(anyhow!("no private key in {}", path))
+}
+
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// A CA with a server certificate for localhost and a client certificate,
+/// written as pem files into a temporary directory.
+#[cfg(test)]
+pub(crate) struct TestPki {
+    dir: tempfile::TempDir,
+}
+
+#[cfg(test)]
+impl TestPki {
+    pub(crate) fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let mut params = rcgen::CertificateParams::new(vec![]);
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(params).unwrap();
+        std::fs::write(dir.path().join("ca.crt"), ca.serialize_pem().unwrap()).unwrap();
+
+        for name in ["localhost", "client"] {
+            let params = rcgen::CertificateParams::new(vec![name.to_string()]);
+            let cert = rcgen::Certificate::from_params(params).unwrap();
+            let pem = cert.serialize_pem_with_signer(&ca).unwrap();
+            std::fs::write(dir.path().join(format!("{}.crt", name)), pem).unwrap();
+            let key = cert.serialize_private_key_pem();
+            std::fs::write(dir.path().join(format!("{}.key", name)), key).unwrap();
+        }
+
+        Self { dir }
+    }
+
+    /// Path of one of ca.crt, localhost.crt/key or client.crt/key.
+    pub(crate) fn path(&self, name: &str) -> String {
+        self.dir.path().join(name).to_str().unwrap().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::server::AllowAnyAuthenticatedClient;
+    use tokio::io::{duplex, AsyncReadExt, DuplexStream};
+    use tokio_rustls::TlsAcceptor;
+
+    fn config(common: &str) -> Config {
+        let mut cfg = Config::new();
+        let content = format!(
+            "[common]\nserver_addr = localhost\ntls_enable = true\n{}",
+            common
+        );
+        cfg.load_config_str(&content).unwrap();
+        cfg
+    }
+
+    // reads frp's head byte, then runs the server side of the handshake and
+    // answers one ping
+    async fn serve(mut stream: DuplexStream, config: ServerConfig) -> Result<Vec<Certificate>> {
+        let mut head = [0];
+        stream.read_exact(&mut head).await?;
+        assert_eq!(head[0], FRP_TLS_HEAD_BYTE);
+
+        let mut stream = TlsAcceptor::from(Arc::new(config)).accept(stream).await?;
+        let mut ping = [0; 4];
+        stream.read_exact(&mut ping).await?;
+        stream.write_all(b"pong").await?;
+        stream.flush().await?;
+
+        Ok(stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .unwrap_or_default()
+            .to_vec())
+    }
+
+    async fn ping(stream: DuplexStream, cfg: &Config) -> Result<()> {
+        let mut stream = connect(stream, cfg, true).await?;
+        stream.write_all(b"ping").await?;
+        let mut pong = [0; 4];
+        stream.read_exact(&mut pong).await?;
+        assert_eq!(&pong, b"pong");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn head_byte_goes_first() {
+        let pki = TestPki::new();
+        let server_config =
+            server_config(&pki.path("localhost.crt"), &pki.path("localhost.key")).unwrap();
+        let cfg = config("");
+        let (client_end, server_end) = duplex(65536);
+        let (client, server) =
+            tokio::join!(ping(client_end, &cfg), serve(server_end, server_config));
+        client.unwrap();
+        assert!(server.unwrap().is_empty());
+
+        // without it the client hello comes first
+        let (client_end, mut server_end) = duplex(65536);
+        tokio::spawn(async move { connect(client_end, &cfg, false).await });
+        let mut head = [0];
+        server_end.read_exact(&mut head).await.unwrap();
+        assert_eq!(head[0], 0x16);
+    }
+
+    #[tokio::test]
+    async fn trusted_ca_rejects_unknown_server() {
+        let pki = TestPki::new();
+        let server_config =
+            || server_config(&pki.path("localhost.crt"), &pki.path("localhost.key")).unwrap();
+
+        let cfg = config(&format!("tls_trusted_ca_file = {}", pki.path("ca.crt")));
+        let (client_end, server_end) = duplex(65536);
+        let (client, server) =
+            tokio::join!(ping(client_end, &cfg), serve(server_end, server_config()));
+        client.unwrap();
+        server.unwrap();
+
+        let other = TestPki::new();
+        let cfg = config(&format!("tls_trusted_ca_file = {}", other.path("ca.crt")));
+        let (client_end, server_end) = duplex(65536);
+        let (client, server) =
+            tokio::join!(ping(client_end, &cfg), serve(server_end, server_config()));
+        let e = client.err().unwrap();
+        assert!(format!("{:#}", e).contains("tls handshake"), "{:#}", e);
+        assert!(server.is_err());
+    }
+
+    #[tokio::test]
+    async fn client_certificate_is_presented() {
+        let pki = TestPki::new();
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&load_certs(&pki.path("ca.crt")).unwrap()[0])
+            .unwrap();
+        let server_config = || {
+            ServerConfig::builder()
+                .with_safe_defaults()
+                .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots.clone()))
+                .with_single_cert(
+                    load_certs(&pki.path("localhost.crt")).unwrap(),
+                    load_key(&pki.path("localhost.key")).unwrap(),
+                )
+                .unwrap()
+        };
+
+        let cfg = config(&format!(
+            "tls_cert_file = {}\ntls_key_file = {}",
+            pki.path("client.crt"),
+            pki.path("client.key")
+        ));
+        let (client_end, server_end) = duplex(65536);
+        let (client, server) =
+            tokio::join!(ping(client_end, &cfg), serve(server_end, server_config()));
+        client.unwrap();
+        assert_eq!(
+            server.unwrap(),
+            load_certs(&pki.path("client.crt")).unwrap()
+        );
+
+        // a server requiring client auth turns away clients without a certificate
+        let cfg = config("");
+        let (client_end, server_end) = duplex(65536);
+        let (client, server) =
+            tokio::join!(ping(client_end, &cfg), serve(server_end, server_config()));
+        assert!(client.is_err());
+        assert!(server.is_err());
+    }
+}
@@ -0,0 +1,326 @@
+use anyhow::{anyhow, Context, Result};
+use futures::{
+    future::{self, BoxFuture},
+    io::{AsyncRead, AsyncWrite},
+    FutureExt, StreamExt,
+};
+use std::{fmt, sync::Arc};
+use tokio::{
+    io::{AsyncRead as TAsyncRead, AsyncWrite as TAsyncWrite},
+    task,
+};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+use yamux::{Config as YamuxConfig, Connection, Control, Mode, WindowUpdateMode};
+
+use crate::{
+    config::Config,
+    dialer,
+    kcp::{KcpConfig, KcpStream},
+    quic, tls, websocket,
+};
+
+/// A byte stream to frps: a yamux stream, a QUIC stream or a whole transport
+/// connection.
+pub trait ServerStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ServerStream for T {}
+
+pub type BoxedStream = Box<dyn ServerStream>;
+
+/// Dials single connections to frps, with tls and the websocket upgrade
+/// layered on as configured.
+pub trait Transport: fmt::Debug + Send + Sync {
+    fn connect(&self) -> BoxFuture<'_, Result<BoxedStream>>;
+}
+
+/// Opens the streams that carry the control, work and visitor connections.
+pub trait StreamOpener: fmt::Debug + Send + Sync {
+    fn open_stream(&self) -> BoxFuture<'_, Result<BoxedStream>>;
+
+    fn close(&self) -> BoxFuture<'_, ()>;
+}
+
+/// Picks the stream opener for the configured protocol and tcp_mux.
+pub async fn open(cfg: &Config) -> Result<Arc<dyn StreamOpener>> {
+    // QUIC brings its own streams, tcp_mux doesn't apply
+    if cfg.protocol() == "quic" {
+        let conn = dialer::connect_each(cfg, cfg.server_addr(), cfg.server_port(), |addr| {
+            quic::connect(addr, cfg)
+        })
+        .await?;
+        return Ok(Arc::new(QuicOpener { conn }));
+    }
+
+    let transport: Arc<dyn Transport> = match cfg.protocol() {
+        "tcp" | "websocket" => Arc::new(TcpTransport { cfg: cfg.clone() }),
+        "kcp" => Arc::new(KcpTransport { cfg: cfg.clone() }),
+        protocol => return Err(anyhow!("unsupported protocol {}", protocol)),
+    };
+    if !cfg.tcp_mux() {
+        return Ok(Arc::new(DirectOpener { transport }));
+    }
+
+    let stream = transport.connect().await?;
+    let conn = Connection::new(stream, yamux_config(), Mode::Client);
+    let ctrl = conn.control();
+    task::spawn(yamux::into_stream(conn).for_each(|_| future::ready(())));
+
+    Ok(Arc::new(MuxOpener { ctrl }))
+}
+
+pub fn yamux_config() -> YamuxConfig {
+    let mut yamux_cfg = YamuxConfig::default();
+    yamux_cfg.set_split_send_size(crate::PAYLOAD_SIZE);
+    yamux_cfg.set_window_update_mode(WindowUpdateMode::OnRead);
+
+    yamux_cfg
+}
+
+#[derive(Debug)]
+struct TcpTransport {
+    cfg: Config,
+}
+
+impl Transport for TcpTransport {
+    fn connect(&self) -> BoxFuture<'_, Result<BoxedStream>> {
+        async move {
+            // frp only routes tcp based protocols through http_proxy
+            if !self.cfg.http_proxy().is_empty() {
+                let stream = dialer::connect_via_proxy(
+                    &self.cfg,
+                    self.cfg.server_addr(),
+                    self.cfg.server_port(),
+                )
+                .await?;
+                return wrap_stream(stream, &self.cfg).await;
+            }
+
+            let stream =
+                dialer::connect_tcp(&self.cfg, self.cfg.server_addr(), self.cfg.server_port())
+                    .await
+                    .context("connect to server")?;
+
+            wrap_stream(stream, &self.cfg).await
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug)]
+struct KcpTransport {
+    cfg: Config,
+}
+
+impl Transport for KcpTransport {
+    fn connect(&self) -> BoxFuture<'_, Result<BoxedStream>> {
+        async move {
+            let cfg = &self.cfg;
+            let socket = dialer::connect_each(
+                cfg,
+                cfg.server_addr(),
+                cfg.server_port(),
+                |addr| async move {
+                    let socket = dialer::bind_udp(cfg, addr).await?;
+                    socket.connect(addr).await?;
+                    Ok(socket)
+                },
+            )
+            .await
+            .context("connect to server")?;
+
+            wrap_stream(KcpStream::new(socket, KcpConfig::transport()), &self.cfg).await
+        }
+        .boxed()
+    }
+}
+
+/// yamux streams over one transport connection.
+#[derive(Debug)]
+struct MuxOpener {
+    ctrl: Control,
+}
+
+impl StreamOpener for MuxOpener {
+    fn open_stream(&self) -> BoxFuture<'_, Result<BoxedStream>> {
+        let mut ctrl = self.ctrl.clone();
+        async move { Ok(Box::new(ctrl.open_stream().await?) as BoxedStream) }.boxed()
+    }
+
+    fn close(&self) -> BoxFuture<'_, ()> {
+        let mut ctrl = self.ctrl.clone();
+        async move {
+            if let Err(e) = ctrl.close().await {
+                println!("close yamux connection error {}", e);
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Native QUIC streams, no yamux involved.
+#[derive(Debug)]
+struct QuicOpener {
+    conn: quinn::Connection,
+}
+
+impl StreamOpener for QuicOpener {
+    fn open_stream(&self) -> BoxFuture<'_, Result<BoxedStream>> {
+        async move { Ok(Box::new(quic::open_stream(&self.conn).await?.compat()) as BoxedStream) }
+            .boxed()
+    }
+
+    fn close(&self) -> BoxFuture<'_, ()> {
+        self.conn.close(0u32.into(), b"");
+        future::ready(()).boxed()
+    }
+}
+
+/// A new transport connection for every stream, used when tcp_mux = false.
+#[derive(Debug)]
+struct DirectOpener {
+    transport: Arc<dyn Transport>,
+}
+
+impl StreamOpener for DirectOpener {
+    fn open_stream(&self) -> BoxFuture<'_, Result<BoxedStream>> {
+        self.transport.connect()
+    }
+
+    fn close(&self) -> BoxFuture<'_, ()> {
+        future::ready(()).boxed()
+    }
+}
+
+/// Layers tls and the websocket upgrade, when configured, over a connected
+/// transport stream.
+async fn wrap_stream<S>(stream: S, cfg: &Config) -> Result<BoxedStream>
+where
+    S: TAsyncRead + TAsyncWrite + Unpin + Send + 'static,
+{
+    match (cfg.protocol() == "websocket", cfg.tls_enable()) {
+        (false, true) => {
+            let head_byte = !cfg.disable_custom_tls_first_byte();
+            Ok(Box::new(
+                tls::connect(stream, cfg, head_byte).await?.compat(),
+            ))
+        }
+        (false, false) => Ok(Box::new(stream.compat())),
+        (true, true) => {
+            // wss may pass https-only proxies and front ends, which refuse frp's marker
+            let stream = tls::connect(stream, cfg, false).await?;
+            Ok(Box::new(websocket::connect(stream, cfg).await?))
+        }
+        (true, false) => Ok(Box::new(websocket::connect(stream, cfg).await?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::TestPki;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use quinn::{crypto::rustls::HandshakeData, Endpoint, ServerConfig};
+    use tokio::io::{duplex, AsyncReadExt as _};
+
+    #[tokio::test]
+    async fn tls_head_byte_only_on_plain_tls() {
+        let cases = [
+            ("tcp", "", 0x17),
+            ("websocket", "", 0x16),
+            ("tcp", "disable_custom_tls_first_byte = true", 0x16),
+            ("websocket", "disable_custom_tls_first_byte = true", 0x16),
+        ];
+        for (protocol, option, head) in cases {
+            let mut cfg = Config::new();
+            let content = format!(
+                "[common]\nserver_addr = localhost\nprotocol = {}\ntls_enable = true\n{}\n",
+                protocol, option
+            );
+            cfg.load_config_str(&content).unwrap();
+
+            let (client_end, mut server_end) = duplex(65536);
+            let client = tokio::spawn(async move { wrap_stream(client_end, &cfg).await.map(drop) });
+            let mut first = [0];
+            server_end.read_exact(&mut first).await.unwrap();
+            assert_eq!(first[0], head, "{} {}", protocol, option);
+            drop(server_end);
+            assert!(client.await.unwrap().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn quic_streams_with_frp_alpn() {
+        let pki = TestPki::new();
+        let mut tls_config =
+            tls::server_config(&pki.path("localhost.crt"), &pki.path("localhost.key")).unwrap();
+        tls_config.alpn_protocols = vec![b"frp".to_vec()];
+        let server_config = ServerConfig::with_crypto(Arc::new(tls_config));
+        let endpoint = Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let mut cfg = Config::new();
+        let content = format!(
+            "[common]\nserver_addr = 127.0.0.1\nserver_port = {}\nprotocol = quic\n\
+             tls_enable = true\ntls_server_name = localhost\ntls_trusted_ca_file = {}\n",
+            endpoint.local_addr().unwrap().port(),
+            pki.path("ca.crt"),
+        );
+        cfg.load_config_str(&content).unwrap();
+
+        // frps: echo every stream of the first connection
+        let frps = tokio::spawn(async move {
+            let mut connecting = endpoint.accept().await.unwrap();
+            let handshake = connecting.handshake_data().await.unwrap();
+            let handshake = handshake.downcast::<HandshakeData>().unwrap();
+            assert_eq!(handshake.protocol.as_deref(), Some(&b"frp"[..]));
+            let conn = connecting.await.unwrap();
+            while let Ok((mut send, recv)) = conn.accept_bi().await {
+                tokio::spawn(async move {
+                    let data = recv.read_to_end(1024).await.unwrap();
+                    send.write_all(&data).await.unwrap();
+                    send.finish().await.unwrap();
+                });
+            }
+        });
+
+        let opener = open(&cfg).await.unwrap();
+        let mut streams = Vec::new();
+        for name in ["control", "work"] {
+            let mut stream = opener.open_stream().await.unwrap();
+            stream.write_all(name.as_bytes()).await.unwrap();
+            stream.close().await.unwrap();
+            streams.push((name, stream));
+        }
+        for (name, mut stream) in streams {
+            let mut echo = Vec::new();
+            stream.read_to_end(&mut echo).await.unwrap();
+            assert_eq!(echo, name.as_bytes());
+        }
+
+        opener.close().await;
+        frps.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn tcp_mux_off_dials_per_stream() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut cfg = Config::new();
+        let content = format!(
+            "[common]\nserver_addr = 127.0.0.1\nserver_port = {}\ntcp_mux = false\n",
+            listener.local_addr().unwrap().port()
+        );
+        cfg.load_config_str(&content).unwrap();
+
+        let opener = open(&cfg).await.unwrap();
+        for name in [&b"control"[..], b"work"] {
+            let mut stream = opener.open_stream().await.unwrap();
+            stream.write_all(name).await.unwrap();
+            stream.close().await.unwrap();
+
+            // a fresh connection carrying only this stream's bytes, no yamux framing
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut data = Vec::new();
+            conn.read_to_end(&mut data).await.unwrap();
+            assert_eq!(data, name);
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use anyhow::{anyhow, Result};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    sync::mpsc::{self, Sender},
+    task::JoinHandle,
+    time::{interval, timeout_at, Instant},
+};
+
+use crate::msg::{read_msg, write_msg, Message, Ping, UdpAddr, UdpPacket};
+
+// a local socket with no packet in either direction for this long is dropped
+// from the session table
+const UDP_SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const UDP_WORK_CONN_HEARTBEAT: Duration = Duration::from_secs(30);
+const UDP_SEND_QUEUE_SIZE: usize = 1024;
+
+/// One visitor address relayed to the local udp service.
+struct UdpSession {
+    socket: Arc<UdpSocket>,
+    last_active: Arc<Mutex<Instant>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for UdpSession {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+type SessionTable = Arc<Mutex<HashMap<String, UdpSession>>>;
+
+/// Relays `UDPPacket` messages read from a udp proxy work connection to the
+/// local service and sends replies back with the visitor address frps gave us.
+pub async fn handle_udp_work_conn<S>(
+    work_stream: S,
+    local_addr: &str,
+    packet_size: usize,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let local_addr = lookup_host(local_addr)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow!("can't resolve local address {}", local_addr))?;
+    let (mut reader, mut writer) = work_stream.split();
+    let (send_tx, mut send_rx) = mpsc::channel::<Message>(UDP_SEND_QUEUE_SIZE);
+
+    let writer_task = tokio::spawn(async move {
+        while let Some(msg) = send_rx.recv().await {
+            if let Err(e) = write_msg(&mut writer, &msg).await {
+                println!("udp work connection write error {:#}", e);
+                break;
+            }
+        }
+    });
+
+    let heartbeat_tx = send_tx.clone();
+    let heartbeat_task = tokio::spawn(async move {
+        let mut ticker = interval(UDP_WORK_CONN_HEARTBEAT);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if heartbeat_tx
+                .send(Message::Ping(Ping::default()))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    let sessions: SessionTable = Arc::new(Mutex::new(HashMap::new()));
+    let res = loop {
+        let packet = match read_msg(&mut reader).await {
+            Ok(Message::UdpPacket(packet)) => packet,
+            Ok(msg) => {
+                println!(
+                    "unexpected message {:?} on udp work connection",
+                    msg.msg_type()
+                );
+                continue;
+            }
+            Err(e) => break Err(e),
+        };
+
+        if let Err(e) = forward_packet(&sessions, &send_tx, packet, local_addr, packet_size).await {
+            println!("forward udp packet error {:#}", e);
+        }
+    };
+
+    sessions.lock().unwrap().clear();
+    heartbeat_task.abort();
+    writer_task.abort();
+
+    res
+}
+
+async fn forward_packet(
+    sessions: &SessionTable,
+    send_tx: &Sender<Message>,
+    packet: UdpPacket,
+    local_addr: std::net::SocketAddr,
+    packet_size: usize,
+) -> Result<()> {
+    let remote_addr = packet
+        .remote_addr
+        .ok_or_else(|| anyhow!("udp packet without remote address"))?;
+    let content = base64::decode(&packet.content)?;
+    let key = format!("{}:{}", remote_addr.ip, remote_addr.port);
+
+    let existing = sessions.lock().unwrap().get(&key).map(|session| {
+        *session.last_active.lock().unwrap() = Instant::now();
+        session.socket.clone()
+    });
+    let socket = match existing {
+        Some(socket) => socket,
+        None => {
+            let bind_addr = if local_addr.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            let socket = Arc::new(UdpSocket::bind(bind_addr).await?);
+            socket.connect(local_addr).await?;
+
+            let last_active = Arc::new(Mutex::new(Instant::now()));
+            let task = tokio::spawn(relay_replies(
+                socket.clone(),
+                last_active.clone(),
+                sessions.clone(),
+                send_tx.clone(),
+                key.clone(),
+                remote_addr,
+                packet_size,
+            ));
+            sessions.lock().unwrap().insert(
+                key,
+                UdpSession {
+                    socket: socket.clone(),
+                    last_active,
+                    task,
+                },
+            );
+            socket
+        }
+    };
+
+    socket.send(&content).await?;
+
+    Ok(())
+}
+
+async fn relay_replies(
+    socket: Arc<UdpSocket>,
+    last_active: Arc<Mutex<Instant>>,
+    sessions: SessionTable,
+    send_tx: Sender<Message>,
+    key: String,
+    remote_addr: UdpAddr,
+    packet_size: usize,
+) {
+    let mut buf = vec![0; packet_size];
+    loop {
+        let deadline = *last_active.lock().unwrap() + UDP_SESSION_IDLE_TIMEOUT;
+        let n = match timeout_at(deadline, socket.recv(&mut buf)).await {
+            Ok(Ok(n)) => n,
+            Ok(Err(e)) => {
+                println!("udp session {} read error {}", key, e);
+                break;
+            }
+            // packets from the user may have moved the deadline meanwhile
+            Err(_) if last_active.lock().unwrap().elapsed() < UDP_SESSION_IDLE_TIMEOUT => continue,
+            Err(_) => break,
+        };
+        *last_active.lock().unwrap() = Instant::now();
+
+        let packet = UdpPacket {
+            content: base64::encode(&buf[..n]),
+            local_addr: None,
+            remote_addr: Some(remote_addr.clone()),
+        };
+        // drop the reply rather than stall every session when the work connection is slow
+        if send_tx.try_send(Message::UdpPacket(packet)).is_err() && send_tx.is_closed() {
+            break;
+        }
+    }
+
+    // removing ourselves aborts this task, so it must be the last thing we do
+    let session = sessions.lock().unwrap().remove(&key);
+    drop(session);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::{ReadHalf, WriteHalf};
+    use std::net::SocketAddr;
+    use tokio::{
+        io::{duplex, DuplexStream},
+        time::sleep,
+    };
+    use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+    type FrpsEnd = Compat<DuplexStream>;
+
+    fn user(port: u16) -> UdpAddr {
+        UdpAddr {
+            ip: "203.0.113.7".to_string(),
+            port,
+            ..Default::default()
+        }
+    }
+
+    // starts a udp work connection to `local_addr`, returns frps's end of it
+    fn work_conn(local_addr: SocketAddr) -> (ReadHalf<FrpsEnd>, WriteHalf<FrpsEnd>) {
+        let (client_end, server_end) = duplex(65536);
+        tokio::spawn(async move {
+            handle_udp_work_conn(client_end.compat(), &local_addr.to_string(), 1500).await
+        });
+
+        server_end.compat().split()
+    }
+
+    // the paused clock jumps ahead whenever the runtime idles, also while a
+    // datagram is on its way, so poll instead of waiting
+    async fn recv_from(socket: &UdpSocket) -> SocketAddr {
+        let mut buf = [0; 1500];
+        loop {
+            match socket.try_recv_from(&mut buf) {
+                Ok((_, from)) => return from,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    tokio::task::yield_now().await
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    async fn send(frps: &mut WriteHalf<FrpsEnd>, from: u16, content: &[u8]) {
+        let packet = UdpPacket {
+            content: base64::encode(content),
+            local_addr: None,
+            remote_addr: Some(user(from)),
+        };
+        write_msg(frps, &Message::UdpPacket(packet)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn replies_go_back_to_each_user() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (mut reader, mut writer) = work_conn(echo.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            while let Ok((n, from)) = echo.recv_from(&mut buf).await {
+                echo.send_to(&buf[..n], from).await.unwrap();
+            }
+        });
+
+        send(&mut writer, 1000, b"from the first user").await;
+        send(&mut writer, 2000, b"from the second user").await;
+        let mut replies = HashMap::new();
+        while replies.len() < 2 {
+            match read_msg(&mut reader).await.unwrap() {
+                Message::UdpPacket(packet) => {
+                    let content = base64::decode(&packet.content).unwrap();
+                    replies.insert(packet.remote_addr.unwrap().port, content);
+                }
+                msg => panic!("unexpected {:?}", msg.msg_type()),
+            }
+        }
+        assert_eq!(replies[&1000], b"from the first user");
+        assert_eq!(replies[&2000], b"from the second user");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn user_packets_keep_the_session() {
+        // a service that never answers
+        let service = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (_reader, mut writer) = work_conn(service.local_addr().unwrap());
+
+        send(&mut writer, 1000, b"hello").await;
+        let session = recv_from(&service).await;
+        for _ in 0..4 {
+            sleep(UDP_SESSION_IDLE_TIMEOUT / 2).await;
+            send(&mut writer, 1000, b"still there").await;
+            assert_eq!(recv_from(&service).await, session);
+        }
+
+        sleep(UDP_SESSION_IDLE_TIMEOUT + Duration::from_secs(1)).await;
+        send(&mut writer, 1000, b"back again").await;
+        let from = recv_from(&service).await;
+        assert_ne!(from, session);
+    }
+}
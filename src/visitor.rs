@@ -0,0 +1,237 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use crate::{
+    config::ClientVisitorConfig,
+    control::proxy,
+    msg::{read_msg, write_msg, Message, NewVisitorConn},
+    service::Service,
+    stream, xtcp,
+};
+
+/// Runs the local listeners of all visitors for one control session. The
+/// listeners are shut down when the manager is dropped.
+pub struct VisitorManager {
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl VisitorManager {
+    pub fn start(service: &Service, configs: &HashMap<String, ClientVisitorConfig>) -> Self {
+        let mut tasks = Vec::new();
+        for (name, visitor_config) in configs {
+            let name = name.clone();
+            let visitor_config = visitor_config.clone();
+            let service = service.clone();
+            tasks.push(tokio::spawn(async move {
+                if let Err(e) = run_visitor(&name, visitor_config, service).await {
+                    println!("visitor [{}] exit: {:#}", name, e);
+                }
+            }));
+        }
+
+        Self { tasks }
+    }
+}
+
+impl Drop for VisitorManager {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+async fn run_visitor(
+    name: &str,
+    visitor_config: ClientVisitorConfig,
+    service: Service,
+) -> Result<()> {
+    let bind_addr = format!("{}:{}", visitor_config.bind_addr, visitor_config.bind_port);
+    let listener = TcpListener::bind(&bind_addr).await?;
+    println!("visitor [{}] listen on {}", name, bind_addr);
+
+    loop {
+        let (user_conn, peer_addr) = listener.accept().await?;
+        let visitor_config = visitor_config.clone();
+        let service = service.clone();
+        let name = name.to_string();
+        tokio::spawn(async move {
+            let res = if visitor_config.service_type == "xtcp" {
+                handle_xtcp_visitor_conn(user_conn, &visitor_config, service).await
+            } else {
+                handle_stcp_visitor_conn(user_conn, &visitor_config, service).await
+            };
+            if let Err(e) = res {
+                println!(
+                    "visitor [{}] connection from {} error: {:#}",
+                    name, peer_addr, e
+                );
+            }
+        });
+    }
+}
+
+async fn handle_stcp_visitor_conn(
+    user_conn: TcpStream,
+    visitor_config: &ClientVisitorConfig,
+    service: Service,
+) -> Result<()> {
+    let mut visitor_stream = service.open_stream().await?;
+    let mut visitor_conn = NewVisitorConn::new(&visitor_config.server_name, &visitor_config.sk);
+    visitor_conn.set_use_encryption(visitor_config.use_encryption);
+    visitor_conn.set_use_compression(visitor_config.use_compression);
+    write_msg(&mut visitor_stream, &Message::NewVisitorConn(visitor_conn)).await?;
+
+    match read_msg(&mut visitor_stream).await? {
+        Message::NewVisitorConnResp(resp) if resp.error.is_empty() => (),
+        Message::NewVisitorConnResp(resp) => {
+            return Err(anyhow!(
+                "start new visitor connection error: {}",
+                resp.error
+            ))
+        }
+        msg => {
+            return Err(anyhow!(
+                "expect new visitor conn response, got {:?}",
+                msg.msg_type()
+            ))
+        }
+    }
+
+    // frps decrypts the visitor side with the secret key before relaying it
+    let key = visitor_config
+        .use_encryption
+        .then(|| &visitor_config.sk[..]);
+    let visitor_stream = stream::with_options(visitor_stream, key, visitor_config.use_compression);
+    proxy(user_conn, visitor_stream).await?;
+
+    Ok(())
+}
+
+async fn handle_xtcp_visitor_conn(
+    user_conn: TcpStream,
+    visitor_config: &ClientVisitorConfig,
+    service: Service,
+) -> Result<()> {
+    let (mut ctrl, p2p_stream) = xtcp::connect_xtcp_visitor(visitor_config, &service).await?;
+    let key = visitor_config
+        .use_encryption
+        .then(|| &visitor_config.sk[..]);
+    let p2p_stream = stream::with_options(p2p_stream, key, visitor_config.use_compression);
+    let res = proxy(user_conn, p2p_stream).await;
+    ctrl.close().await?;
+    res?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, msg::NewVisitorConnResp, stream::EncryptedStream};
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use tokio::io::{duplex, AsyncReadExt as _, AsyncWriteExt as _, DuplexStream};
+    use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+    const CONFIG: &str = "
+[secret_ssh_visitor]
+type = stcp
+role = visitor
+server_name = secret_ssh
+sk = abcdefg
+use_encryption = true
+";
+
+    // a visitor service whose only stream to frps is the returned duplex end
+    fn visitor() -> (ClientVisitorConfig, Service, Compat<DuplexStream>) {
+        let mut cfg = Config::new();
+        cfg.load_config_str(CONFIG).unwrap();
+        let visitor_config = cfg.visitor_configs["secret_ssh_visitor"].clone();
+        let (client_end, server_end) = duplex(65536);
+        let service = Service::with_streams(cfg, vec![Box::new(client_end.compat())]);
+
+        (visitor_config, service, server_end.compat())
+    }
+
+    async fn user_conn() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let user = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+
+        (user, accepted)
+    }
+
+    // reads NewVisitorConn and checks it is signed with the secret key
+    async fn expect_visitor_conn(frps: &mut Compat<DuplexStream>) {
+        let visitor_conn = match read_msg(frps).await.unwrap() {
+            Message::NewVisitorConn(visitor_conn) => serde_json::to_value(visitor_conn).unwrap(),
+            msg => panic!("expect new visitor conn, got {:?}", msg.msg_type()),
+        };
+        assert_eq!(visitor_conn["proxy_name"], "secret_ssh");
+        assert_eq!(visitor_conn["use_encryption"], true);
+        assert_eq!(visitor_conn["use_compression"], false);
+        let timestamp = visitor_conn["timestamp"].as_i64().unwrap();
+        let sign_key = format!("{:x}", md5::compute(format!("abcdefg{}", timestamp)));
+        assert_eq!(visitor_conn["sign_key"], sign_key);
+    }
+
+    fn visitor_conn_resp(error: &str) -> Message {
+        let resp: NewVisitorConnResp = serde_json::from_value(serde_json::json!({
+            "proxy_name": "secret_ssh",
+            "error": error,
+        }))
+        .unwrap();
+        Message::NewVisitorConnResp(resp)
+    }
+
+    #[tokio::test]
+    async fn stcp_visitor_relays_encrypted_with_sk() {
+        let (visitor_config, service, mut frps) = visitor();
+        let (mut user, accepted) = user_conn().await;
+        let visitor = tokio::spawn(async move {
+            handle_stcp_visitor_conn(accepted, &visitor_config, service).await
+        });
+
+        expect_visitor_conn(&mut frps).await;
+        write_msg(&mut frps, &visitor_conn_resp("")).await.unwrap();
+
+        // frps decrypts the visitor side with sk
+        let mut frps = EncryptedStream::new(frps, "abcdefg");
+        user.write_all(b"ping").await.unwrap();
+        let mut ping = [0; 4];
+        frps.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+        frps.write_all(b"pong").await.unwrap();
+        frps.flush().await.unwrap();
+        let mut pong = [0; 4];
+        user.read_exact(&mut pong).await.unwrap();
+        assert_eq!(&pong, b"pong");
+
+        drop(user);
+        visitor.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn stcp_visitor_reports_error_resp() {
+        let (visitor_config, service, mut frps) = visitor();
+        let (_user, accepted) = user_conn().await;
+        let visitor = tokio::spawn(async move {
+            handle_stcp_visitor_conn(accepted, &visitor_config, service).await
+        });
+
+        expect_visitor_conn(&mut frps).await;
+        let error = "specified proxy [secret_ssh] not found";
+        write_msg(&mut frps, &visitor_conn_resp(error))
+            .await
+            .unwrap();
+
+        let e = visitor.await.unwrap().unwrap_err();
+        assert!(e.to_string().contains(error), "{}", e);
+    }
+}
@@ -0,0 +1,189 @@
+use anyhow::{Context as _, Result};
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    ready, Sink, Stream,
+};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead as TAsyncRead, AsyncWrite as TAsyncWrite};
+use tokio_tungstenite::{
+    client_async,
+    tungstenite::{client::IntoClientRequest, http::HeaderValue, Message},
+    WebSocketStream,
+};
+
+use crate::config::Config;
+
+// frps serves websocket clients on its bind port under this path
+const FRP_WEBSOCKET_PATH: &str = "/~!frp";
+
+/// Runs the websocket upgrade with frps over an established connection,
+/// plain or tls, and returns it as a byte stream for yamux.
+pub async fn connect<S>(stream: S, cfg: &Config) -> Result<WsStream<S>>
+where
+    S: TAsyncRead + TAsyncWrite + Unpin,
+{
+    let (scheme, origin) = if cfg.tls_enable() {
+        ("wss", "https")
+    } else {
+        ("ws", "http")
+    };
+    let addr = format!("{}:{}", cfg.server_addr(), cfg.server_port());
+    let mut request =
+        format!("{}://{}{}", scheme, addr, FRP_WEBSOCKET_PATH).into_client_request()?;
+    // frps uses golang.org/x/net/websocket, which refuses handshakes without an origin
+    request.headers_mut().insert(
+        "Origin",
+        HeaderValue::from_str(&format!("{}://{}", origin, addr))?,
+    );
+
+    let (ws, _) = client_async(request, stream)
+        .await
+        .context("websocket handshake with server")?;
+
+    Ok(WsStream {
+        ws,
+        read_buf: Vec::new(),
+        read_pos: 0,
+    })
+}
+
+/// Byte stream over binary websocket frames.
+pub struct WsStream<S> {
+    ws: WebSocketStream<S>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: TAsyncRead + TAsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        while this.read_pos >= this.read_buf.len() {
+            match ready!(Pin::new(&mut this.ws).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    this.read_buf = data;
+                    this.read_pos = 0;
+                }
+                Some(Ok(Message::Text(text))) => {
+                    this.read_buf = text.into_bytes();
+                    this.read_pos = 0;
+                }
+                // pings are answered by tungstenite itself
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => (),
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(0)),
+                Some(Err(e)) => return Poll::Ready(Err(to_io_error(e))),
+            }
+        }
+
+        let n = buf.len().min(this.read_buf.len() - this.read_pos);
+        buf[..n].copy_from_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+        this.read_pos += n;
+
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: TAsyncRead + TAsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let ws = Pin::new(&mut self.ws);
+        ready!(ws.poll_ready(cx)).map_err(to_io_error)?;
+        Pin::new(&mut self.ws)
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(to_io_error)?;
+        // get the frame going now, the caller may not flush until it has more
+        if let Poll::Ready(Err(e)) = Pin::new(&mut self.ws).poll_flush(cx) {
+            return Poll::Ready(Err(to_io_error(e)));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.ws).poll_flush(cx).map_err(to_io_error)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.ws).poll_close(cx).map_err(to_io_error)
+    }
+}
+
+fn to_io_error(e: tokio_tungstenite::tungstenite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport;
+    use futures::{future, AsyncReadExt, AsyncWriteExt, StreamExt};
+    use tokio::io::duplex;
+    use tokio_tungstenite::{accept_hdr_async, tungstenite::handshake::server};
+    use yamux::{Connection, Mode};
+
+    // the handshake callback's error type is tungstenite's, not ours to shrink
+    #[allow(clippy::result_large_err)]
+    #[tokio::test]
+    async fn yamux_over_binary_frames() {
+        let mut cfg = Config::new();
+        cfg.load_config_str("[common]\nserver_addr = 127.0.0.1\nserver_port = 7000\n")
+            .unwrap();
+        let (client_end, server_end) = duplex(65536);
+
+        // frps: check the upgrade request, then echo every yamux stream
+        let frps = tokio::spawn(async move {
+            let check = |req: &server::Request, resp: server::Response| {
+                assert_eq!(req.uri().path(), FRP_WEBSOCKET_PATH);
+                assert_eq!(req.headers()["Origin"], "http://127.0.0.1:7000");
+                Ok(resp)
+            };
+            let ws = accept_hdr_async(server_end, check).await.unwrap();
+            let stream = WsStream {
+                ws,
+                read_buf: Vec::new(),
+                read_pos: 0,
+            };
+            let mut conn = Connection::new(stream, transport::yamux_config(), Mode::Server);
+            while let Some(mut stream) = conn.next_stream().await.unwrap() {
+                tokio::spawn(async move {
+                    let mut data = Vec::new();
+                    stream.read_to_end(&mut data).await.unwrap();
+                    stream.write_all(&data).await.unwrap();
+                    stream.close().await.unwrap();
+                });
+            }
+        });
+
+        let stream = connect(client_end, &cfg).await.unwrap();
+        let conn = Connection::new(stream, transport::yamux_config(), Mode::Client);
+        let mut ctrl = conn.control();
+        tokio::spawn(yamux::into_stream(conn).for_each(|_| future::ready(())));
+
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let mut stream = ctrl.open_stream().await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream.close().await.unwrap();
+        let mut echo = Vec::new();
+        stream.read_to_end(&mut echo).await.unwrap();
+        assert_eq!(echo, data);
+
+        ctrl.close().await.unwrap();
+        frps.await.unwrap();
+    }
+}
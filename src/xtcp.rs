@@ -0,0 +1,289 @@
+use anyhow::{anyhow, Result};
+use futures::{future, io::AsyncRead, io::AsyncWrite, StreamExt};
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    time::{interval, timeout},
+};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+use yamux::{Connection, Control, Mode, Stream};
+
+use crate::{
+    config::ClientVisitorConfig,
+    dialer,
+    kcp::{KcpConfig, KcpStream},
+    msg::{
+        decode_msg, read_msg, write_msg, Message, NatHoleClient, NatHoleClientDetectOK,
+        NatHoleResp, NatHoleVisitor,
+    },
+    service::Service,
+    stream,
+    transport::{self, BoxedStream},
+};
+
+const NAT_HOLE_CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+const NAT_HOLE_VISITOR_TIMEOUT: Duration = Duration::from_secs(10);
+const NAT_HOLE_SID_TIMEOUT: Duration = Duration::from_secs(8);
+// the visitor repeats its sid until the echo arrives, the first copies may hit
+// the peer's NAT before it has opened a mapping for us
+const NAT_HOLE_SID_RESEND: Duration = Duration::from_millis(300);
+// low enough to open our own NAT mapping without reaching the peer's NAT
+const NAT_HOLE_DETECT_TTL: u32 = 3;
+
+/// Punches a hole towards the visitor frps reports for this work connection
+/// and returns the visitor's stream over kcp on the resulting udp path.
+///
+/// The order follows frp 0.44's client: read NatHoleSid from the work
+/// connection, exchange NatHoleClient/NatHoleResp on frps's udp port, send the
+/// sid towards the visitor with a low TTL, then report NatHoleClientDetectOK on
+/// the work connection, which is what makes frps answer the visitor.
+pub async fn accept_xtcp_work_conn<S>(
+    mut work_stream: S,
+    service: &Service,
+    proxy_name: &str,
+    encryption_key: Option<&str>,
+    use_compression: bool,
+) -> Result<BoxedStream>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let sid = match read_msg(&mut work_stream).await? {
+        Message::NatHoleSid(msg) => msg.sid,
+        msg => return Err(anyhow!("expect nat hole sid, got {:?}", msg.msg_type())),
+    };
+
+    let nat_hole_client = Message::NatHoleClient(NatHoleClient::new(proxy_name, &sid));
+    let (socket, resp) = exchange(service, &nat_hole_client, NAT_HOLE_CLIENT_TIMEOUT).await?;
+    println!(
+        "xtcp [{}] sid {} visitor address {}, client address {}",
+        proxy_name, resp.sid, resp.visitor_addr, resp.client_addr
+    );
+
+    let visitor_addr = resolve(&resp.visitor_addr).await?;
+    if visitor_addr.is_ipv4() {
+        let ttl = socket.ttl()?;
+        socket.set_ttl(NAT_HOLE_DETECT_TTL)?;
+        socket.send_to(sid.as_bytes(), visitor_addr).await?;
+        socket.set_ttl(ttl)?;
+    }
+    write_msg(
+        &mut work_stream,
+        &Message::NatHoleClientDetectOK(NatHoleClientDetectOK::default()),
+    )
+    .await?;
+
+    let mut buf = [0; 1024];
+    let peer_addr = timeout(NAT_HOLE_SID_TIMEOUT, async {
+        loop {
+            let (n, from) = socket.recv_from(&mut buf).await?;
+            if &buf[..n] == sid.as_bytes() {
+                return Ok::<_, anyhow::Error>(from);
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("wait for sid from visitor timeout"))??;
+    socket.send_to(sid.as_bytes(), peer_addr).await?;
+    socket.connect(peer_addr).await?;
+    println!("xtcp [{}] hole punched with {}", proxy_name, peer_addr);
+    // frps is done with the work connection once the visitor got its answer
+    drop(work_stream);
+
+    let kcp_stream = KcpStream::new(socket, KcpConfig::xtcp());
+    let mut conn = Connection::new(kcp_stream.compat(), transport::yamux_config(), Mode::Server);
+    let p2p_stream = conn
+        .next_stream()
+        .await?
+        .ok_or_else(|| anyhow!("p2p session closed before any stream"))?;
+    tokio::spawn(yamux::into_stream(conn).for_each(|_| future::ready(())));
+
+    Ok(stream::with_options(
+        p2p_stream,
+        encryption_key,
+        use_compression,
+    ))
+}
+
+/// Asks frps to connect us with the xtcp proxy named in the visitor config and
+/// opens a stream to it over kcp. The returned control keeps the p2p session
+/// and should be closed once the stream is done.
+pub async fn connect_xtcp_visitor(
+    visitor_config: &ClientVisitorConfig,
+    service: &Service,
+) -> Result<(Control, Stream)> {
+    let nat_hole_visitor = Message::NatHoleVisitor(NatHoleVisitor::new(
+        &visitor_config.server_name,
+        &visitor_config.sk,
+    ));
+    let (socket, resp) = exchange(service, &nat_hole_visitor, NAT_HOLE_VISITOR_TIMEOUT).await?;
+
+    let client_addr = resolve(&resp.client_addr).await?;
+    socket.connect(client_addr).await?;
+
+    let sid = resp.sid.as_bytes();
+    let mut buf = [0; 1024];
+    let mut resend = interval(NAT_HOLE_SID_RESEND);
+    timeout(NAT_HOLE_SID_TIMEOUT, async {
+        loop {
+            tokio::select! {
+                _ = resend.tick() => {
+                    socket.send(sid).await?;
+                }
+                n = socket.recv(&mut buf) => {
+                    if &buf[..n?] == sid {
+                        return Ok::<_, anyhow::Error>(());
+                    }
+                }
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("wait for sid echo from {} timeout", client_addr))??;
+
+    let kcp_stream = KcpStream::new(socket, KcpConfig::xtcp());
+    let conn = Connection::new(kcp_stream.compat(), transport::yamux_config(), Mode::Client);
+    let mut ctrl = conn.control();
+    tokio::spawn(yamux::into_stream(conn).for_each(|_| future::ready(())));
+    let p2p_stream = ctrl.open_stream().await?;
+
+    Ok((ctrl, p2p_stream))
+}
+
+/// Sends one nat hole message to frps's udp port and waits for its response,
+/// trying each resolved server address in turn. The socket is bound like the
+/// other server connections, on connect_server_local_ip when it is set.
+async fn exchange(
+    service: &Service,
+    msg: &Message,
+    wait: Duration,
+) -> Result<(UdpSocket, NatHoleResp)> {
+    if service.server_udp_port == 0 {
+        return Err(anyhow!("frps has no bind_udp_port, xtcp is not available"));
+    }
+
+    let cfg = &service.cfg;
+    let data = msg.encode()?;
+    dialer::connect_each(
+        cfg,
+        cfg.server_addr(),
+        service.server_udp_port,
+        |server_addr| {
+            let data = &data;
+            async move {
+                let socket = dialer::bind_udp(cfg, server_addr).await?;
+                socket.send_to(data, server_addr).await?;
+
+                let mut buf = [0; 1024];
+                let msg = timeout(wait, async {
+                    loop {
+                        let (n, from) = socket.recv_from(&mut buf).await?;
+                        if from == server_addr {
+                            return decode_msg(&buf[..n]);
+                        }
+                    }
+                })
+                .await
+                .map_err(|_| anyhow!("wait for nat hole response timeout"))??;
+
+                match msg {
+                    Message::NatHoleResp(resp) if resp.error.is_empty() => Ok((socket, resp)),
+                    Message::NatHoleResp(resp) => Err(anyhow!("nat hole error: {}", resp.error)),
+                    msg => Err(anyhow!("expect nat hole resp, got {:?}", msg.msg_type())),
+                }
+            }
+        },
+    )
+    .await
+}
+
+async fn resolve(addr: &str) -> Result<SocketAddr> {
+    lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow!("can't resolve address {}", addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, msg::NatHoleSid};
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use tokio::io::duplex;
+
+    // plays frps and the visitor, checking each step of the client's handshake
+    #[tokio::test]
+    async fn xtcp_work_conn_handshake() {
+        let frps_udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let visitor = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut cfg = Config::new();
+        cfg.load_config_str("[common]\nserver_addr = 127.0.0.1\n")
+            .unwrap();
+        let mut service = Service::with_streams(cfg, vec![]);
+        service.server_udp_port = frps_udp.local_addr().unwrap().port();
+
+        let (client_end, server_end) = duplex(4096);
+        let client = tokio::spawn(async move {
+            accept_xtcp_work_conn(client_end.compat(), &service, "p2p", None, false).await
+        });
+        let mut work_conn = server_end.compat();
+        let sid = "sid-1234";
+        let nat_hole_sid = NatHoleSid {
+            sid: sid.to_string(),
+        };
+        write_msg(&mut work_conn, &Message::NatHoleSid(nat_hole_sid))
+            .await
+            .unwrap();
+
+        // 1. NatHoleClient on frps's udp port
+        let mut buf = [0; 1024];
+        let (n, client_addr) = frps_udp.recv_from(&mut buf).await.unwrap();
+        match decode_msg(&buf[..n]).unwrap() {
+            Message::NatHoleClient(msg) => {
+                let msg = serde_json::to_value(msg).unwrap();
+                assert_eq!(msg["proxy_name"], "p2p");
+                assert_eq!(msg["sid"], sid);
+            }
+            msg => panic!("expect nat hole client, got {:?}", msg.msg_type()),
+        }
+        let resp = NatHoleResp {
+            sid: sid.to_string(),
+            visitor_addr: visitor.local_addr().unwrap().to_string(),
+            client_addr: client_addr.to_string(),
+            error: "".to_string(),
+        };
+        let resp = Message::NatHoleResp(resp).encode().unwrap();
+        frps_udp.send_to(&resp, client_addr).await.unwrap();
+
+        // 2. NatHoleClientDetectOK on the work connection, only after the sid
+        // went out towards the visitor
+        match read_msg(&mut work_conn).await.unwrap() {
+            Message::NatHoleClientDetectOK(_) => (),
+            msg => panic!("expect detect ok, got {:?}", msg.msg_type()),
+        }
+        let (n, from) = visitor.try_recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n], sid.as_bytes());
+        assert_eq!(from, client_addr);
+
+        // 3. the visitor's sid is echoed back
+        visitor.send_to(sid.as_bytes(), client_addr).await.unwrap();
+        let (n, _) = visitor.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], sid.as_bytes());
+        let mut rest = Vec::new();
+        work_conn.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+
+        // 4. a yamux stream over kcp reaches the client
+        visitor.connect(client_addr).await.unwrap();
+        let kcp_stream = KcpStream::new(visitor, KcpConfig::xtcp());
+        let conn = Connection::new(kcp_stream.compat(), transport::yamux_config(), Mode::Client);
+        let mut ctrl = conn.control();
+        tokio::spawn(yamux::into_stream(conn).for_each(|_| future::ready(())));
+        let mut p2p_stream = ctrl.open_stream().await.unwrap();
+        p2p_stream.write_all(b"hello").await.unwrap();
+
+        let mut work_stream = client.await.unwrap().unwrap();
+        let mut hello = [0; 5];
+        work_stream.read_exact(&mut hello).await.unwrap();
+        assert_eq!(&hello, b"hello");
+    }
+}